@@ -0,0 +1,180 @@
+//! Captures NSDP replies of a switch as a corpus entry of `tests/corpus`.
+//!
+//! ```text
+//! cargo run --example capture -- <hostname> <if_name> > tests/corpus/<model>.toml
+//! ```
+//!
+//! Please check the decoded values against the web interface of the switch before contributing the file.
+
+use failure::Error;
+use futures::Future;
+use prosafe_exporter::{Duplex, Link, ProSafeSwitch, SwitchFuture, Transport, UdpTransport};
+use serde::Serialize;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Recorder
+// ---------------------------------------------------------------------------------------------------------------------
+
+struct Recorder {
+    inner: UdpTransport,
+    last_reply: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Transport for Recorder {
+    fn host_mac(&self) -> Result<[u8; 6], Error> {
+        self.inner.host_mac()
+    }
+
+    fn exchange(&self, request: Vec<u8>, deadline: Instant) -> SwitchFuture<Vec<u8>> {
+        let last_reply = Arc::clone(&self.last_reply);
+        Box::new(self.inner.exchange(request, deadline).map(move |x| {
+            *last_reply.lock().unwrap() = x.clone();
+            x
+        }))
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Corpus entry
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[derive(Serialize)]
+struct Entry {
+    model: String,
+    source: String,
+    info: Info,
+    port_stat: PortStat,
+    speed_stat: SpeedStat,
+}
+
+#[derive(Serialize)]
+struct Info {
+    reply: String,
+    model: String,
+    name: String,
+    mac: String,
+    firmware: String,
+}
+
+#[derive(Serialize)]
+struct PortStat {
+    reply: String,
+    expected: Vec<PortStatExpected>,
+}
+
+#[derive(Serialize)]
+struct PortStatExpected {
+    port_no: u8,
+    recv_bytes: u64,
+    send_bytes: u64,
+    error_pkts: u64,
+}
+
+#[derive(Serialize)]
+struct SpeedStat {
+    reply: String,
+    expected: Vec<SpeedStatExpected>,
+}
+
+#[derive(Serialize)]
+struct SpeedStatExpected {
+    port_no: u8,
+    link: String,
+    duplex: String,
+}
+
+fn hex(dat: &[u8]) -> String {
+    dat.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Main
+// ---------------------------------------------------------------------------------------------------------------------
+
+fn run() -> Result<(), Error> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        return Err(failure::format_err!("usage: capture <hostname> <if_name>"));
+    }
+
+    let last_reply = Arc::new(Mutex::new(Vec::new()));
+    let recorder = Recorder {
+        inner: UdpTransport::new(&args[1], &args[2]),
+        last_reply: Arc::clone(&last_reply),
+    };
+    let sw = ProSafeSwitch::with_transport(&args[1], Arc::new(recorder));
+    let reply = || hex(&last_reply.lock().unwrap());
+
+    let info = sw.info()?;
+    let info = Info {
+        reply: reply(),
+        model: info.model,
+        name: info.name,
+        mac: info.mac,
+        firmware: info.firmware,
+    };
+
+    let port_stat = sw.port_stat()?;
+    let port_stat = PortStat {
+        reply: reply(),
+        expected: port_stat
+            .stats
+            .into_iter()
+            .map(|x| PortStatExpected {
+                port_no: x.port_no,
+                recv_bytes: x.recv_bytes,
+                send_bytes: x.send_bytes,
+                error_pkts: x.error_pkts,
+            })
+            .collect(),
+    };
+
+    let speed_stat = sw.speed_stat()?;
+    let speed_stat = SpeedStat {
+        reply: reply(),
+        expected: speed_stat
+            .stats
+            .into_iter()
+            .map(|x| SpeedStatExpected {
+                port_no: x.port_no,
+                link: match x.link {
+                    Link::None => "None",
+                    Link::Speed10Mbps => "Speed10Mbps",
+                    Link::Speed100Mbps => "Speed100Mbps",
+                    Link::Speed1Gbps => "Speed1Gbps",
+                    Link::Speed10Gbps => "Speed10Gbps",
+                    _ => "Unknown",
+                }
+                .to_string(),
+                duplex: match x.duplex {
+                    Duplex::None => "down",
+                    Duplex::Half => "half",
+                    Duplex::Full => "full",
+                    _ => "unknown",
+                }
+                .to_string(),
+            })
+            .collect(),
+    };
+
+    let entry = Entry {
+        model: info.model.clone(),
+        source: format!("firmware {}", info.firmware),
+        info,
+        port_stat,
+        speed_stat,
+    };
+    print!("{}", toml::to_string(&entry)?);
+
+    Ok(())
+}
+
+fn main() {
+    if let Err(x) = run() {
+        eprintln!("{}", x);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use prosafe_exporter::{PortCount, PortStats, SpeedStats, SwitchInfo};
+
+// Every decoder must reject malformed replies with an error, never panic.
+fuzz_target!(|data: &[u8]| {
+    let _ = PortStats::decode(data);
+    let _ = SpeedStats::decode(data);
+    let _ = PortCount::decode(data);
+    let _ = SwitchInfo::decode(data);
+});
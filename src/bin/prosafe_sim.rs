@@ -0,0 +1,42 @@
+use failure::Error;
+use prosafe_exporter::simulator::{Simulator, SimulatorConfig};
+use std::fs;
+use std::path::PathBuf;
+use structopt::{clap, StructOpt};
+
+// -------------------------------------------------------------------------------------------------
+// Opt
+// -------------------------------------------------------------------------------------------------
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "prosafe-sim")]
+#[structopt(long_version = option_env!("LONG_VERSION").unwrap_or(env!("CARGO_PKG_VERSION")))]
+#[structopt(setting = clap::AppSettings::ColoredHelp)]
+#[structopt(setting = clap::AppSettings::DeriveDisplayOrder)]
+pub struct Opt {
+    /// TOML file describing virtual switches.
+    #[structopt(parse(from_os_str))]
+    pub config: PathBuf,
+
+    /// Show verbose message
+    #[structopt(short = "v", long = "verbose")]
+    pub verbose: bool,
+}
+
+// -------------------------------------------------------------------------------------------------
+// Main
+// -------------------------------------------------------------------------------------------------
+
+fn run() -> Result<(), Error> {
+    let opt = Opt::from_args();
+    let config: SimulatorConfig = toml::from_str(&fs::read_to_string(&opt.config)?)?;
+    let server = Simulator::serve(&config, opt.verbose)?;
+    tokio::run(server);
+    Ok(())
+}
+
+fn main() {
+    if let Err(x) = run() {
+        println!("{}", x);
+    }
+}
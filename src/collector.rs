@@ -0,0 +1,823 @@
+use crate::prosafe_switch::{
+    Capabilities, Capability, PortCount, PortStats, ProSafeSwitch, SpeedStats, SwitchFuture,
+    SwitchInfo,
+};
+use failure::format_err;
+use failure::Error;
+use futures::{future, stream, Future, Stream};
+use lazy_static::lazy_static;
+use prometheus::core::{Collector as MetricCollector, Desc};
+use prometheus::proto::{Counter, Gauge, LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::Opts;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::runtime::current_thread::Runtime;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Metrics
+// ---------------------------------------------------------------------------------------------------------------------
+
+lazy_static! {
+    static ref UP_OPT: Opts = Opts::new("prosafe_up", "The last query is successful.");
+    static ref RECEIVE_BYTES_OPT: Opts =
+        Opts::new("prosafe_receive_bytes_total", "Incoming transfer in bytes.");
+    static ref TRANSMIT_BYTES_OPT: Opts = Opts::new(
+        "prosafe_transmit_bytes_total",
+        "Outgoing transfer in bytes."
+    );
+    static ref ERROR_PACKETS_OPT: Opts =
+        Opts::new("prosafe_error_packets_total", "Transfer error in packets.");
+    static ref LINK_SPEED_OPT: Opts = Opts::new("prosafe_link_speed", "Link speed in Mbps.");
+    static ref PORT_COUNT_OPT: Opts = Opts::new("prosafe_port_count", "Number of ports.");
+    static ref LAST_SUCCESS_OPT: Opts = Opts::new(
+        "prosafe_last_success_timestamp_seconds",
+        "Last time the switch was polled successfully in seconds since the epoch."
+    );
+    static ref INFO_OPT: Opts = Opts::new(
+        "prosafe_info",
+        "A metric with a constant '1' value labeled by model, name, mac and firmware."
+    );
+    static ref PROBE_DURATION_OPT: Opts = Opts::new(
+        "prosafe_probe_duration_seconds",
+        "Duration of the probe of the switch in seconds."
+    );
+    static ref COLLECTOR_SUCCESS_OPT: Opts = Opts::new(
+        "prosafe_collector_success",
+        "Whether the query of the collector was successful."
+    );
+    static ref COLLECTOR_DURATION_OPT: Opts = Opts::new(
+        "prosafe_collector_duration_seconds",
+        "Duration of the query of the collector in seconds."
+    );
+}
+
+/// Labels added to all metrics of a switch.
+pub type Labels = Vec<(String, String)>;
+
+/// Capabilities of switches by hostname.
+pub type CapabilitiesCache = Arc<Mutex<HashMap<String, Capabilities>>>;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Collector
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// A group of metrics queried from a switch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Collector {
+    /// `prosafe_info`
+    Info,
+    /// `prosafe_port_count`
+    PortCount,
+    /// `prosafe_receive_bytes_total`, `prosafe_transmit_bytes_total` and `prosafe_error_packets_total`
+    PortStat,
+    /// `prosafe_link_speed`
+    SpeedStat,
+}
+
+impl Collector {
+    pub const ALL: [Collector; 4] = [
+        Collector::Info,
+        Collector::PortCount,
+        Collector::PortStat,
+        Collector::SpeedStat,
+    ];
+
+    /// Returns the name used in the configuration file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Collector::Info => "info",
+            Collector::PortCount => "port_count",
+            Collector::PortStat => "port_stat",
+            Collector::SpeedStat => "speed_stat",
+        }
+    }
+}
+
+impl fmt::Display for Collector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Collector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Collector::ALL
+            .iter()
+            .copied()
+            .find(|x| x.name() == s)
+            .ok_or_else(|| format_err!("unknown collector '{}'", s))
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// ProbeResult
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Values queried from a switch. The queries which failed or were not run are `None`.
+#[derive(Clone, Debug, Default)]
+pub struct ProbeResult {
+    pub up: bool,
+    /// Network interface through which the switch was accessed.
+    pub interface: String,
+    pub info: Option<SwitchInfo>,
+    pub port_count: Option<PortCount>,
+    pub port_stats: Option<PortStats>,
+    pub speed_stats: Option<SpeedStats>,
+    /// Last time the switch was polled successfully in the background.
+    pub last_success: Option<SystemTime>,
+    /// Duration of the whole probe.
+    pub duration: Option<Duration>,
+    /// Results of the collectors which were run.
+    pub collectors: Vec<CollectorResult>,
+    /// Message of the last failure in the probe.
+    pub error: Option<String>,
+}
+
+/// Result of a collector in a probe.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CollectorResult {
+    pub collector: Collector,
+    pub success: bool,
+    pub duration: Duration,
+    /// Message of the failure.
+    pub error: Option<String>,
+}
+
+impl ProbeResult {
+    /// Copies the values and the result of `collector` from `other`.
+    pub fn copy_collector(&mut self, collector: Collector, other: &ProbeResult) {
+        match collector {
+            Collector::Info => self.info = other.info.clone(),
+            Collector::PortCount => self.port_count = other.port_count.clone(),
+            Collector::PortStat => self.port_stats = other.port_stats.clone(),
+            Collector::SpeedStat => self.speed_stats = other.speed_stats.clone(),
+        }
+        self.collectors.retain(|x| x.collector != collector);
+        self.collectors.extend(
+            other
+                .collectors
+                .iter()
+                .filter(|x| x.collector == collector)
+                .cloned(),
+        );
+    }
+
+    /// Adds the result of a collector. Its failure becomes the error of the probe.
+    fn add_collector(&mut self, status: Option<CollectorResult>) {
+        if let Some(status) = status {
+            if status.error.is_some() {
+                self.error = status.error.clone();
+            }
+            self.collectors.push(status);
+        }
+    }
+
+    /// Queries `sw` by `collectors` until the deadline of each query, and `deadline` of the whole probe if given.
+    ///
+    /// The capabilities of the switch are found at the first access, and kept in `cache` by the hostname.
+    /// The collectors not supported by the switch are skipped, and the collectors which could not start before
+    /// `deadline` fail without queries.
+    pub fn query_async(
+        sw: &ProSafeSwitch,
+        collectors: &[Collector],
+        cache: &CapabilitiesCache,
+        deadline: Option<Instant>,
+    ) -> SwitchFuture<ProbeResult> {
+        let start = Instant::now();
+        let collects = |x| collectors.contains(&x);
+        let host = String::from(sw.hostname());
+
+        // The model is required to find the capabilities unless they are cached.
+        let collect_info = collects(Collector::Info);
+        let cached = cache.lock().unwrap().contains_key(&host);
+
+        let sw0 = sw.clone();
+        let info = timed(
+            Collector::Info,
+            collect_info || !cached,
+            deadline,
+            move || sw0.info_async(until(&sw0, deadline)),
+        );
+
+        let sw0 = sw.clone();
+        let cache = Arc::clone(cache);
+        let caps = info.and_then(move |(info, status)| {
+            capabilities(&sw0, &host, info.as_ref(), &cache, deadline).map(move |caps| {
+                let mut result = ProbeResult {
+                    up: info.is_some(),
+                    ..Default::default()
+                };
+                if collect_info {
+                    result.info = info;
+                    result.add_collector(status);
+                } else {
+                    result.error = status.and_then(|x| x.error);
+                }
+                (result, caps)
+            })
+        });
+
+        let sw0 = sw.clone();
+        let collect_port_count = collects(Collector::PortCount);
+        let port_count = caps.and_then(move |(mut result, caps)| {
+            let supported = collect_port_count && caps.supports(Capability::PortCount);
+            let profile = caps.clone();
+            timed(Collector::PortCount, supported, deadline, move || {
+                Box::new(
+                    sw0.port_count_async(until(&sw0, deadline))
+                        .and_then(move |count| {
+                            profile.validate_port_count(&count)?;
+                            Ok(count)
+                        }),
+                )
+            })
+            .map(move |(x, status)| {
+                result.port_count = x;
+                result.add_collector(status);
+                (result, caps)
+            })
+        });
+
+        let sw0 = sw.clone();
+        let collect_port_stat = collects(Collector::PortStat);
+        let port_stats = port_count.and_then(move |(mut result, caps)| {
+            let supported = collect_port_stat && caps.supports(Capability::PortStat);
+            let count = result.port_count.clone().or_else(|| caps.port_count());
+            timed(Collector::PortStat, supported, deadline, move || {
+                Box::new(
+                    sw0.port_stat_async(until(&sw0, deadline))
+                        .and_then(move |stats| {
+                            if let Some(ref count) = count {
+                                stats.validate(count)?;
+                            }
+                            Ok(stats)
+                        }),
+                )
+            })
+            .map(move |(x, status)| {
+                result.port_stats = x;
+                result.add_collector(status);
+                (result, caps)
+            })
+        });
+
+        let sw0 = sw.clone();
+        let supported = collects(Collector::SpeedStat);
+        Box::new(port_stats.and_then(move |(mut result, caps)| {
+            let supported = supported && caps.supports(Capability::SpeedStat);
+            let count = result.port_count.clone().or_else(|| caps.port_count());
+            let profile = caps.clone();
+            timed(Collector::SpeedStat, supported, deadline, move || {
+                Box::new(
+                    sw0.speed_stat_async(until(&sw0, deadline))
+                        .and_then(move |stats| {
+                            if let Some(ref count) = count {
+                                stats.validate(count)?;
+                            }
+                            profile.validate(&stats)?;
+                            Ok(stats)
+                        }),
+                )
+            })
+            .map(move |(x, status)| {
+                result.speed_stats = x;
+                result.add_collector(status);
+                // Port statistics decide it if queried, otherwise any successful query does.
+                result.up = if collect_port_stat && caps.supports(Capability::PortStat) {
+                    result.port_stats.is_some()
+                } else {
+                    result.up || result.port_count.is_some() || result.speed_stats.is_some()
+                };
+                result.duration = Some(start.elapsed());
+                result
+            })
+        }))
+    }
+}
+
+/// Returns the deadline of a query starting now, which doesn't exceed the deadline of the whole probe.
+fn until(sw: &ProSafeSwitch, deadline: Option<Instant>) -> Instant {
+    let x = sw.deadline();
+    deadline.map_or(x, |deadline| x.min(deadline))
+}
+
+/// Runs the query if `supported`, and returns the value and the result of `collector`.
+///
+/// A failed query is logged and turned into `None` so that the other collectors still run.
+/// The query is not run after `deadline`, and the collector fails.
+fn timed<T, F>(
+    collector: Collector,
+    supported: bool,
+    deadline: Option<Instant>,
+    query: F,
+) -> SwitchFuture<(Option<T>, Option<CollectorResult>)>
+where
+    T: Send + 'static,
+    F: FnOnce() -> SwitchFuture<T>,
+{
+    if !supported {
+        return Box::new(future::ok((None, None)));
+    }
+    let start = Instant::now();
+    if deadline.is_some_and(|x| start >= x) {
+        let status = CollectorResult {
+            collector,
+            success: false,
+            duration: Duration::from_secs(0),
+            error: Some(String::from("not run by the deadline of the probe")),
+        };
+        return Box::new(future::ok((None, Some(status))));
+    }
+    Box::new(query().then(move |x| {
+        let (x, error) = match x {
+            Ok(x) => (Some(x), None),
+            Err(x) => {
+                eprintln!("Fail to access: {}", x);
+                (None, Some(format!("{}", x)))
+            }
+        };
+        let status = CollectorResult {
+            collector,
+            success: x.is_some(),
+            duration: start.elapsed(),
+            error,
+        };
+        Ok((x, Some(status)))
+    }))
+}
+
+fn capabilities(
+    sw: &ProSafeSwitch,
+    host: &str,
+    info: Option<&SwitchInfo>,
+    cache: &CapabilitiesCache,
+    deadline: Option<Instant>,
+) -> SwitchFuture<Capabilities> {
+    if let Some(caps) = cache.lock().unwrap().get(host) {
+        return Box::new(future::ok(caps.clone()));
+    }
+
+    // Unreachable switches are queried for everything so that failures are still reported.
+    let info = match info {
+        Some(x) => x,
+        None => return Box::new(future::ok(Capabilities::all())),
+    };
+
+    let caps = match Capabilities::from_profile(&info.model) {
+        Some(x) => Box::new(future::ok(x)),
+        None => sw.probe_capabilities_async(until(sw, deadline)),
+    };
+
+    let host = String::from(host);
+    let cache = Arc::clone(cache);
+    Box::new(caps.then(move |x| match x {
+        Ok(caps) => {
+            cache.lock().unwrap().insert(host, caps.clone());
+            Ok(caps)
+        }
+        Err(x) => {
+            eprintln!("Fail to probe capabilities: {}", x);
+            Ok(Capabilities::all())
+        }
+    }))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// ProSafeCollector
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Prometheus collector querying switches at each collection.
+///
+/// It produces the same metric families as `/probe` of the exporter except `prosafe_build_info`.
+/// The byte and packet counters of the switches are exposed as counters converted from `u64` directly, which is
+/// exact up to 2^53. Link speed, port count and up are gauges.
+///
+/// The switches are queried concurrently on a temporary runtime like the blocking methods of [`ProSafeSwitch`],
+/// so the collector must not be gathered from a tokio runtime. The switches bound to the same NSDP port of the host
+/// by [`ProSafeSwitch::host_port`] are queried one at a time, and the switches created with
+/// [`UdpMux::connector`](crate::UdpMux::connector) share the port concurrently.
+///
+/// ```no_run
+/// use prosafe_exporter::collector::ProSafeCollector;
+/// use prosafe_exporter::ProSafeSwitch;
+///
+/// let registry = prometheus::Registry::new();
+/// let collector = ProSafeCollector::new(ProSafeSwitch::new("switch1", "eth0"));
+/// registry.register(Box::new(collector)).unwrap();
+/// ```
+pub struct ProSafeCollector {
+    switches: Vec<(ProSafeSwitch, Labels)>,
+    collectors: Vec<Collector>,
+    capabilities: CapabilitiesCache,
+    descs: Vec<Desc>,
+}
+
+impl ProSafeCollector {
+    /// Creates a collector of a switch.
+    pub fn new(switch: ProSafeSwitch) -> Self {
+        ProSafeCollector::build(vec![(switch, Vec::new())])
+    }
+
+    /// Creates a collector of switches, each with the labels added to its metrics.
+    ///
+    /// The switches have the union of the labels, and the missing ones are empty.
+    /// It fails if a label name is invalid or set by the collector like `port`.
+    pub fn with_switches(switches: Vec<(ProSafeSwitch, Labels)>) -> Result<Self, Error> {
+        for (sw, labels) in &switches {
+            for (name, _) in labels {
+                check_label_name(name)
+                    .map_err(|x| format_err!("switch '{}' has {}", sw.hostname(), x))?;
+            }
+        }
+        Ok(ProSafeCollector::build(switches))
+    }
+
+    fn build(switches: Vec<(ProSafeSwitch, Labels)>) -> Self {
+        let mut collector = ProSafeCollector {
+            switches,
+            collectors: Collector::ALL.to_vec(),
+            capabilities: Arc::new(Mutex::new(HashMap::new())),
+            descs: Vec::new(),
+        };
+        let labels: Vec<_> = collector.switches.iter().map(|(_, x)| x).collect();
+        collector.descs = Families::new(&label_names(labels))
+            .into_vec()
+            .iter()
+            .map(Family::desc)
+            .collect();
+        collector
+    }
+
+    /// Sets the collectors to run. All collectors run by default.
+    pub fn collectors(mut self, collectors: &[Collector]) -> Self {
+        self.collectors = collectors.to_vec();
+        self
+    }
+
+    /// Queries the switches concurrently, and returns the results with the labels of each switch.
+    ///
+    /// The switches which failed are `up` false. It fails only if the runtime can't be created.
+    pub fn query(&self) -> Result<Vec<(ProbeResult, Labels)>, Error> {
+        // The switches through the same port of the host are queried in turn.
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        let mut ports: HashMap<u16, usize> = HashMap::new();
+        for (i, (sw, _)) in self.switches.iter().enumerate() {
+            match sw.host_port().and_then(|x| ports.get(&x)) {
+                Some(&group) => groups[group].push(i),
+                None => {
+                    if let Some(port) = sw.host_port() {
+                        ports.insert(port, groups.len());
+                    }
+                    groups.push(vec![i]);
+                }
+            }
+        }
+
+        let queries: Vec<_> = groups
+            .into_iter()
+            .map(|group| {
+                let switches: Vec<_> = group
+                    .into_iter()
+                    .map(|i| (i, self.switches[i].clone()))
+                    .collect();
+                let collectors = self.collectors.clone();
+                let capabilities = Arc::clone(&self.capabilities);
+                stream::iter_ok(switches)
+                    .and_then(move |(i, (sw, labels))| {
+                        ProbeResult::query_async(&sw, &collectors, &capabilities, None)
+                            .then(move |x| Ok::<_, Error>((i, (x.unwrap_or_default(), labels))))
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut runtime = Runtime::new()?;
+        let mut results: Vec<_> = runtime
+            .block_on(future::join_all(queries))?
+            .into_iter()
+            .flatten()
+            .collect();
+        results.sort_by_key(|(i, _)| *i);
+        Ok(results.into_iter().map(|(_, x)| x).collect())
+    }
+}
+
+impl MetricCollector for ProSafeCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        match self.query() {
+            Ok(x) => families(&x),
+            Err(x) => {
+                eprintln!("Fail to query switches: {}", x);
+                Vec::new()
+            }
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Family
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Exact values of integer counters by [`series_key`], which the `f64` values of the metrics lose above 2^53.
+pub type Integers = HashMap<String, u64>;
+
+/// Returns the key of a series by the name and the labels.
+pub fn series_key(name: &str, metric: &Metric) -> String {
+    let mut key = String::from(name);
+    for label in metric.get_label() {
+        key.push_str(&format!(",{}={:?}", label.get_name(), label.get_value()));
+    }
+    key
+}
+
+/// Builder of a metric family.
+struct Family {
+    opts: &'static Opts,
+    kind: MetricType,
+    label: Vec<String>,
+    metrics: Vec<Metric>,
+    integers: Integers,
+}
+
+impl Family {
+    fn new(opts: &'static Opts, kind: MetricType, label: &[String]) -> Self {
+        Family {
+            opts,
+            kind,
+            label: label.to_vec(),
+            metrics: Vec::new(),
+            integers: Integers::new(),
+        }
+    }
+
+    fn desc(&self) -> Desc {
+        Desc::new(
+            self.opts.fq_name(),
+            self.opts.help.clone(),
+            self.label.clone(),
+            HashMap::new(),
+        )
+        .unwrap()
+    }
+
+    fn gauge(&mut self, label: &[&str], value: f64) {
+        let mut gauge = Gauge::default();
+        gauge.set_value(value);
+        let mut metric = self.metric(label);
+        metric.set_gauge(gauge);
+        self.metrics.push(metric);
+    }
+
+    fn counter(&mut self, label: &[&str], value: u64) {
+        let mut counter = Counter::default();
+        counter.set_value(value as f64);
+        let mut metric = self.metric(label);
+        metric.set_counter(counter);
+        self.integers
+            .insert(series_key(&self.opts.fq_name(), &metric), value);
+        self.metrics.push(metric);
+    }
+
+    fn metric(&self, label: &[&str]) -> Metric {
+        let mut label: Vec<_> = self
+            .label
+            .iter()
+            .zip(label)
+            .map(|(k, v)| {
+                let mut pair = LabelPair::default();
+                pair.set_name(k.clone());
+                pair.set_value(String::from(*v));
+                pair
+            })
+            .collect();
+        label.sort_by(|x, y| x.get_name().cmp(y.get_name()));
+
+        let mut metric = Metric::default();
+        metric.set_label(label.into());
+        metric
+    }
+
+    fn finish(mut self) -> MetricFamily {
+        // Sorted like `Registry::gather`.
+        self.metrics.sort_by(|x, y| {
+            let x = x.get_label().iter().map(|x| x.get_value());
+            let y = y.get_label().iter().map(|x| x.get_value());
+            x.cmp(y)
+        });
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.opts.fq_name());
+        family.set_help(self.opts.help.clone());
+        family.set_field_type(self.kind);
+        family.set_metric(self.metrics.into());
+        family
+    }
+}
+
+/// Metric families of switches.
+struct Families {
+    up: Family,
+    receive_bytes: Family,
+    transmit_bytes: Family,
+    error_packets: Family,
+    link_speed: Family,
+    port_count: Family,
+    last_success: Family,
+    info: Family,
+    probe_duration: Family,
+    collector_success: Family,
+    collector_duration: Family,
+}
+
+impl Families {
+    /// Creates the families of switches with the label names `up_label`.
+    fn new(up_label: &[String]) -> Self {
+        let mut port_label = up_label.to_vec();
+        port_label.push(String::from("port"));
+        let mut info_label = up_label.to_vec();
+        info_label.extend(
+            ["model", "name", "mac", "firmware"]
+                .iter()
+                .map(|x| x.to_string()),
+        );
+        let mut collector_label = up_label.to_vec();
+        collector_label.push(String::from("collector"));
+
+        Families {
+            up: Family::new(&UP_OPT, MetricType::GAUGE, up_label),
+            receive_bytes: Family::new(&RECEIVE_BYTES_OPT, MetricType::COUNTER, &port_label),
+            transmit_bytes: Family::new(&TRANSMIT_BYTES_OPT, MetricType::COUNTER, &port_label),
+            error_packets: Family::new(&ERROR_PACKETS_OPT, MetricType::COUNTER, &port_label),
+            link_speed: Family::new(&LINK_SPEED_OPT, MetricType::GAUGE, &port_label),
+            port_count: Family::new(&PORT_COUNT_OPT, MetricType::GAUGE, up_label),
+            last_success: Family::new(&LAST_SUCCESS_OPT, MetricType::GAUGE, up_label),
+            info: Family::new(&INFO_OPT, MetricType::GAUGE, &info_label),
+            probe_duration: Family::new(&PROBE_DURATION_OPT, MetricType::GAUGE, up_label),
+            collector_success: Family::new(
+                &COLLECTOR_SUCCESS_OPT,
+                MetricType::GAUGE,
+                &collector_label,
+            ),
+            collector_duration: Family::new(
+                &COLLECTOR_DURATION_OPT,
+                MetricType::GAUGE,
+                &collector_label,
+            ),
+        }
+    }
+
+    fn into_vec(self) -> Vec<Family> {
+        vec![
+            self.up,
+            self.receive_bytes,
+            self.transmit_bytes,
+            self.error_packets,
+            self.link_speed,
+            self.port_count,
+            self.last_success,
+            self.info,
+            self.probe_duration,
+            self.collector_success,
+            self.collector_duration,
+        ]
+    }
+}
+
+/// Labels set by the collector, which can't be added to switches.
+pub(crate) const RESERVED_LABELS: &[&str] =
+    &["port", "collector", "model", "name", "mac", "firmware"];
+
+/// Checks that `name` is a valid label name which isn't set by the collector.
+pub(crate) fn check_label_name(name: &str) -> Result<(), Error> {
+    let valid = name
+        .chars()
+        .enumerate()
+        .all(|(i, x)| x == '_' || x.is_ascii_alphabetic() || (i > 0 && x.is_ascii_digit()));
+    if !valid || name.is_empty() || name.starts_with("__") {
+        return Err(format_err!("invalid label name '{}'", name));
+    }
+    if RESERVED_LABELS.contains(&name) {
+        return Err(format_err!("reserved label name '{}'", name));
+    }
+    Ok(())
+}
+
+/// Returns the union of the label names in the order of appearance.
+fn label_names<'a, I>(labels: I) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a Labels>,
+{
+    let mut names: Vec<String> = Vec::new();
+    for (k, _) in labels.into_iter().flatten() {
+        if !names.contains(k) {
+            names.push(k.clone());
+        }
+    }
+    names
+}
+
+/// Returns the metric families of the results, each with the labels added to its metrics.
+///
+/// The switches have the union of the labels, and the missing ones are empty. Empty families are omitted.
+pub fn families(results: &[(ProbeResult, Labels)]) -> Vec<MetricFamily> {
+    families_with_integers(results).0
+}
+
+/// Returns the metric families like [`families`], and the exact values of their integer counters.
+pub fn families_with_integers(results: &[(ProbeResult, Labels)]) -> (Vec<MetricFamily>, Integers) {
+    let up_label = label_names(results.iter().map(|(_, x)| x));
+    let mut families = Families::new(&up_label);
+
+    for (result, labels) in results {
+        let label: Vec<&str> = up_label
+            .iter()
+            .map(|k| {
+                labels
+                    .iter()
+                    .find(|(x, _)| x == k)
+                    .map_or("", |(_, v)| v.as_str())
+            })
+            .collect();
+
+        families.up.gauge(&label, if result.up { 1.0 } else { 0.0 });
+
+        if let Some(ref x) = result.info {
+            let mut label = label.clone();
+            label.extend_from_slice(&[
+                x.model.as_str(),
+                x.name.as_str(),
+                x.mac.as_str(),
+                x.firmware.as_str(),
+            ]);
+            families.info.gauge(&label, 1.0);
+        }
+
+        if let Some(x) = result.last_success {
+            let x = x.duration_since(UNIX_EPOCH).unwrap_or_default();
+            families.last_success.gauge(&label, x.as_secs_f64());
+        }
+
+        if let Some(x) = result.duration {
+            families.probe_duration.gauge(&label, x.as_secs_f64());
+        }
+
+        for x in &result.collectors {
+            let mut label = label.clone();
+            label.push(x.collector.name());
+            let success = if x.success { 1.0 } else { 0.0 };
+            families.collector_success.gauge(&label, success);
+            families
+                .collector_duration
+                .gauge(&label, x.duration.as_secs_f64());
+        }
+
+        if let Some(ref count) = result.port_count {
+            families.port_count.gauge(&label, f64::from(count.count));
+        }
+
+        if let Some(ref stats) = result.port_stats {
+            for s in &stats.stats {
+                let port = format!("{}", s.port_no);
+                let mut label = label.clone();
+                label.push(port.as_str());
+
+                families.receive_bytes.counter(&label, s.recv_bytes);
+                families.transmit_bytes.counter(&label, s.send_bytes);
+                families.error_packets.counter(&label, s.error_pkts);
+            }
+        }
+
+        if let Some(ref stats) = result.speed_stats {
+            for s in &stats.stats {
+                let port = format!("{}", s.port_no);
+                let mut label = label.clone();
+                label.push(port.as_str());
+
+                let speed = s.link.mbps();
+                families.link_speed.gauge(&label, f64::from(speed));
+            }
+        }
+    }
+
+    let mut integers = Integers::new();
+    let families = families
+        .into_vec()
+        .into_iter()
+        .filter(|x| !x.metrics.is_empty())
+        .map(|mut x| {
+            integers.extend(x.integers.drain());
+            x.finish()
+        })
+        .collect();
+    (families, integers)
+}
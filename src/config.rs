@@ -0,0 +1,272 @@
+use crate::collector::check_label_name;
+pub use crate::collector::Collector;
+use crate::transport::UdpOptions;
+use failure::format_err;
+use failure::Error;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Config
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Configuration file of the exporter described by TOML.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, rename = "target")]
+    pub targets: Vec<TargetConfig>,
+}
+
+/// A switch referred by name from `/probe?target=<name>` and `--target`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TargetConfig {
+    pub name: String,
+    /// Hostname or IP address of the switch.
+    pub host: String,
+    /// Network interface through which the switch is accessed. `*` searches it.
+    pub interface: String,
+    /// Address to which the socket of the host is bound.
+    pub source_address: Option<IpAddr>,
+    /// NSDP port of the host.
+    pub host_port: u16,
+    /// NSDP port of the switch.
+    pub switch_port: u16,
+    /// Timeout of each query in milliseconds.
+    pub timeout: u64,
+    /// Number of retries of each query.
+    pub retries: u32,
+    /// Interval of the background polling in milliseconds. The default is given by `--poll.interval`.
+    pub interval: Option<u64>,
+    /// Age in milliseconds of the polled result after which its metrics are dropped.
+    /// The default is given by `--poll.staleness`.
+    pub staleness: Option<u64>,
+    /// Collectors to run. All collectors run if it is omitted.
+    pub collectors: Vec<Collector>,
+    /// Intervals in milliseconds during which the results of slow collectors are reused by the following probes.
+    pub cache_interval: BTreeMap<Collector, u64>,
+    /// Labels added to all metrics of the switch.
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Default for TargetConfig {
+    fn default() -> Self {
+        let udp = UdpOptions::default();
+        TargetConfig {
+            name: String::new(),
+            host: String::new(),
+            interface: String::from("*"),
+            source_address: None,
+            host_port: udp.host_port,
+            switch_port: udp.switch_port,
+            timeout: 1000,
+            retries: 0,
+            interval: None,
+            staleness: None,
+            collectors: Collector::ALL.to_vec(),
+            cache_interval: BTreeMap::new(),
+            labels: BTreeMap::new(),
+        }
+    }
+}
+
+/// Labels set by the exporter in addition to the reserved labels of the collector, which can't be used as static
+/// labels.
+const EXPORTER_LABELS: &[&str] = &["instance", "target"];
+
+impl Config {
+    /// Reads and validates the configuration file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path)
+            .map_err(|x| format_err!("failed to read '{}': {}", path.display(), x))?;
+        let config: Config = toml::from_str(&text)
+            .map_err(|x| format_err!("failed to parse '{}': {}", path.display(), x))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that the names are unique and the settings are usable.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut names = HashSet::new();
+        for target in &self.targets {
+            if !names.insert(target.name.as_str()) {
+                return Err(format_err!("target '{}' is defined twice", target.name));
+            }
+            target.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the target named `name`.
+    pub fn target(&self, name: &str) -> Option<&TargetConfig> {
+        self.targets.iter().find(|x| x.name == name)
+    }
+}
+
+impl TargetConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if self.name.is_empty() || self.name.contains(':') {
+            return Err(format_err!(
+                "target name '{}' must be non-empty and must not contain ':'",
+                self.name
+            ));
+        }
+        if self.host.is_empty() {
+            return Err(format_err!("target '{}' has no host", self.name));
+        }
+        if self.timeout == 0 {
+            return Err(format_err!("target '{}' has zero timeout", self.name));
+        }
+        if self.interval == Some(0) {
+            return Err(format_err!("target '{}' has zero interval", self.name));
+        }
+        for label in self.labels.keys() {
+            if EXPORTER_LABELS.contains(&label.as_str()) {
+                return Err(format_err!(
+                    "target '{}' has reserved label name '{}'",
+                    self.name,
+                    label
+                ));
+            }
+            check_label_name(label).map_err(|x| format_err!("target '{}' has {}", self.name, x))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the addresses of NSDP.
+    pub fn udp_options(&self) -> UdpOptions {
+        let default = UdpOptions::default();
+        UdpOptions {
+            source_address: self.source_address.unwrap_or(default.source_address),
+            host_port: self.host_port,
+            switch_port: self.switch_port,
+        }
+    }
+
+    /// Returns the timeout of each query.
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout)
+    }
+
+    /// Returns the interval of the background polling if it is given.
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval.map(Duration::from_millis)
+    }
+
+    /// Returns the staleness limit of the polled result if it is given.
+    pub fn staleness(&self) -> Option<Duration> {
+        self.staleness.map(Duration::from_millis)
+    }
+
+    /// Returns the intervals during which the results of the collectors are reused.
+    pub fn cache_interval(&self) -> BTreeMap<Collector, Duration> {
+        self.cache_interval
+            .iter()
+            .map(|(k, v)| (*k, Duration::from_millis(*v)))
+            .collect()
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config: Config = toml::from_str(
+            r#"
+            [[target]]
+            name           = "switch1"
+            host           = "192.168.0.10"
+            interface      = "eth0"
+            source_address = "192.168.0.2"
+            switch_port    = 10022
+            timeout        = 500
+            retries        = 2
+            interval       = 10000
+            collectors     = ["port_stat", "speed_stat"]
+            cache_interval = { info = 3600000 }
+            labels         = { site = "tokyo", rack = "a1" }
+
+            [[target]]
+            name = "switch2"
+            host = "switch2.local"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let target = config.target("switch1").unwrap();
+        assert_eq!(target.interface, "eth0");
+        assert_eq!(
+            target.udp_options(),
+            UdpOptions {
+                source_address: IpAddr::from([192, 168, 0, 2]),
+                host_port: 63321,
+                switch_port: 10022,
+            }
+        );
+        assert_eq!(target.timeout(), Duration::from_millis(500));
+        assert_eq!(target.retries, 2);
+        assert_eq!(target.interval(), Some(Duration::from_secs(10)));
+        assert_eq!(target.staleness(), None);
+        assert_eq!(
+            target.collectors,
+            vec![Collector::PortStat, Collector::SpeedStat]
+        );
+        assert_eq!(
+            target.cache_interval()[&Collector::Info],
+            Duration::from_secs(3600)
+        );
+        assert_eq!(target.labels["site"], "tokyo");
+
+        let target = config.target("switch2").unwrap();
+        assert_eq!(target.interface, "*");
+        assert_eq!(target.udp_options(), UdpOptions::default());
+        assert_eq!(target.collectors, Collector::ALL.to_vec());
+        assert!(target.cache_interval().is_empty());
+        assert!(config.target("switch3").is_none());
+    }
+
+    #[test]
+    fn test_example() {
+        let config: Config =
+            toml::from_str(include_str!("../example/prosafe_exporter.toml")).unwrap();
+        assert_eq!(config.targets.len(), 2);
+        assert_eq!(config.targets[0].udp_options(), UdpOptions::default());
+    }
+
+    #[test]
+    fn test_validate() {
+        let check = |text: &str| toml::from_str::<Config>(text).unwrap().validate();
+
+        assert!(check("[[target]]\nname = 'a'\nhost = 'h'").is_ok());
+        assert!(
+            check("[[target]]\nname = 'a'\nhost = 'h'\n[[target]]\nname = 'a'\nhost = 'h'")
+                .is_err()
+        );
+        assert!(check("[[target]]\nname = 'a:b'\nhost = 'h'").is_err());
+        assert!(check("[[target]]\nname = 'a'\nhost = 'h'\ninterval = 0").is_err());
+        assert!(check("[[target]]\nname = 'a'\nhost = 'h'\ntimeout = 0").is_err());
+        assert!(check("[[target]]\nname = 'a'").is_err());
+        assert!(check("[[target]]\nname = 'a'\nhost = 'h'\nlabels = { port = '1' }").is_err());
+        assert!(check("[[target]]\nname = 'a'\nhost = 'h'\nlabels = { collector = '1' }").is_err());
+        assert!(check("[[target]]\nname = 'a'\nhost = 'h'\nlabels = { instance = '1' }").is_err());
+        assert!(check("[[target]]\nname = 'a'\nhost = 'h'\nlabels = { rack = '1' }").is_ok());
+        assert!(check("[[target]]\nname = 'a'\nhost = 'h'\nlabels = { 1a = '1' }").is_err());
+        assert!(toml::from_str::<Config>("[[target]]\nname = 'a'\ncollectors = ['poe']").is_err());
+        assert!(
+            toml::from_str::<Config>("[[target]]\nname = 'a'\ncache_interval = { poe = 1 }")
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,476 @@
+use crate::prosafe_switch::{
+    Cmd, PortCount, PortStats, QueryResponse, SpeedStats, SwitchFuture, SwitchInfo,
+};
+use crate::transport::{Connector, ReplayTransport, Transport};
+use failure::format_err;
+use failure::Error;
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// DumpRecord
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// A pair of request and reply written to a dump directory.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DumpRecord {
+    /// Seconds since the Unix epoch when the reply was received.
+    pub timestamp: f64,
+    /// Hostname of the switch.
+    pub target: String,
+    /// Network interface used for the exchange.
+    pub interface: String,
+    /// Seconds taken by the exchange.
+    #[serde(default)]
+    pub duration: f64,
+    /// Request in hex.
+    pub request: String,
+    /// Reply in hex. Empty if the exchange failed.
+    #[serde(default)]
+    pub reply: String,
+    /// Error of the exchange. Empty if it succeeded.
+    #[serde(default)]
+    pub error: String,
+    /// TLVs of the reply.
+    #[serde(default)]
+    pub tlv: Vec<Tlv>,
+    /// Structs decoded from the reply.
+    #[serde(default)]
+    pub decoded: Vec<String>,
+}
+
+/// A TLV record of a reply.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tlv {
+    /// Tag in hex ( e.g. `0x1000` ).
+    pub tag: String,
+    /// Name of the tag, or `Unknown`.
+    pub name: String,
+    /// Length of the value in bytes.
+    pub length: usize,
+    /// Value in hex.
+    pub value: String,
+}
+
+/// A pair of request and reply in bytes.
+pub type Packets = (Vec<u8>, Vec<u8>);
+
+impl DumpRecord {
+    /// Creates a record of an exchange. `reply` is the result of the exchange.
+    pub fn new(
+        target: &str,
+        interface: &str,
+        request: &[u8],
+        reply: Result<&[u8], &Error>,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs_f64())
+            .unwrap_or_default();
+        let (reply, error, tlv, decoded) = match reply {
+            Ok(x) => (hex(x), String::new(), Tlv::decode(x), decode(x)),
+            Err(x) => (String::new(), format!("{}", x), vec![], vec![]),
+        };
+        DumpRecord {
+            timestamp,
+            target: String::from(target),
+            interface: String::from(interface),
+            duration: 0.0,
+            request: hex(request),
+            reply,
+            error,
+            tlv,
+            decoded,
+        }
+    }
+
+    /// Returns the request and the reply in bytes, or `None` if the exchange failed.
+    pub fn packets(&self) -> Result<Option<Packets>, Error> {
+        if !self.error.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some((unhex(&self.request)?, unhex(&self.reply)?)))
+    }
+}
+
+impl Tlv {
+    /// Splits a reply into TLVs. Malformed replies result in no TLV.
+    pub fn decode(dat: &[u8]) -> Vec<Tlv> {
+        QueryResponse::decode(dat)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(tag, value)| Tlv {
+                tag: format!("0x{:04x}", tag),
+                name: Cmd::from_tag(tag)
+                    .map(|x| format!("{:?}", x))
+                    .unwrap_or_else(|| String::from("Unknown")),
+                length: value.len(),
+                value: hex(&value),
+            })
+            .collect()
+    }
+}
+
+/// Decodes a reply into the structs of the contained TLVs, and returns them in debug format.
+pub fn decode(dat: &[u8]) -> Vec<String> {
+    let tags: Vec<u16> = QueryResponse::decode(dat)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(tag, _)| tag)
+        .collect();
+    let has = |cmd: Cmd| tags.contains(&cmd.tag());
+
+    fn debug<T: std::fmt::Debug>(x: Result<T, Error>) -> String {
+        match x {
+            Ok(x) => format!("{:?}", x),
+            Err(x) => format!("Error: {}", x),
+        }
+    }
+
+    let mut ret = Vec::new();
+    if has(Cmd::Model) {
+        ret.push(debug(SwitchInfo::decode(dat)));
+    }
+    if has(Cmd::PortCount) {
+        ret.push(debug(PortCount::decode(dat)));
+    }
+    if has(Cmd::PortStat) {
+        ret.push(debug(PortStats::decode(dat)));
+    }
+    if has(Cmd::SpeedStat) {
+        ret.push(debug(SpeedStats::decode(dat)));
+    }
+    ret
+}
+
+pub(crate) fn hex(dat: &[u8]) -> String {
+    dat.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>, Error> {
+    let s: Vec<char> = s.chars().filter(|x| !x.is_whitespace()).collect();
+    s.chunks(2)
+        .map(|x| {
+            let x: String = x.iter().collect();
+            u8::from_str_radix(&x, 16).map_err(|_| format_err!("invalid hex '{}'", x))
+        })
+        .collect()
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// DumpTransport
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Transport recording every exchange of the inner transport as a [`DumpRecord`].
+///
+/// The records are written to a directory in TOML, or kept in memory.
+/// Failures to write are reported to stderr, and do not affect the exchange.
+#[derive(Clone)]
+pub struct DumpTransport {
+    inner: Arc<dyn Transport>,
+    sink: DumpSink,
+    hostname: String,
+    if_name: String,
+}
+
+/// Records kept in memory by [`DumpTransport::memory`].
+pub type DumpRecords = Arc<Mutex<Vec<DumpRecord>>>;
+
+#[derive(Clone)]
+enum DumpSink {
+    Dir(PathBuf),
+    Memory(DumpRecords),
+}
+
+static DUMP_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+impl DumpTransport {
+    /// Creates a transport writing records to `dir`.
+    pub fn new(inner: Arc<dyn Transport>, dir: &Path, hostname: &str, if_name: &str) -> Self {
+        DumpTransport {
+            inner,
+            sink: DumpSink::Dir(PathBuf::from(dir)),
+            hostname: String::from(hostname),
+            if_name: String::from(if_name),
+        }
+    }
+
+    /// Creates a transport appending records to `records`.
+    pub fn memory(
+        inner: Arc<dyn Transport>,
+        records: DumpRecords,
+        hostname: &str,
+        if_name: &str,
+    ) -> Self {
+        DumpTransport {
+            inner,
+            sink: DumpSink::Memory(records),
+            hostname: String::from(hostname),
+            if_name: String::from(if_name),
+        }
+    }
+
+    fn write(&self, record: DumpRecord) -> Result<(), Error> {
+        let dir = match self.sink {
+            DumpSink::Dir(ref x) => x,
+            DumpSink::Memory(ref x) => {
+                x.lock().unwrap().push(record);
+                return Ok(());
+            }
+        };
+
+        let host: String = self
+            .hostname
+            .chars()
+            .map(|x| {
+                if x.is_ascii_alphanumeric() || x == '.' || x == '-' {
+                    x
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let name = format!(
+            "{:.3}-{}-{:06}.toml",
+            record.timestamp,
+            host,
+            DUMP_SEQ.fetch_add(1, Ordering::SeqCst)
+        );
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(name), toml::to_string(&record)?)?;
+        Ok(())
+    }
+}
+
+impl Transport for DumpTransport {
+    fn host_mac(&self) -> Result<[u8; 6], Error> {
+        self.inner.host_mac()
+    }
+
+    fn exchange(&self, request: Vec<u8>, deadline: Instant) -> SwitchFuture<Vec<u8>> {
+        let dump = self.clone();
+        let start = Instant::now();
+        Box::new(
+            self.inner
+                .exchange(request.clone(), deadline)
+                .then(move |reply| {
+                    let mut record = DumpRecord::new(
+                        &dump.hostname,
+                        &dump.if_name,
+                        &request,
+                        reply.as_ref().map(|x| x.as_slice()),
+                    );
+                    record.duration = start.elapsed().as_secs_f64();
+                    if let Err(x) = dump.write(record) {
+                        eprintln!("Fail to write dump: {}", x);
+                    }
+                    reply
+                }),
+        )
+    }
+
+    fn host_port(&self) -> Option<u16> {
+        self.inner.host_port()
+    }
+}
+
+/// Returns the connector wrapping transports created by `inner` with [`DumpTransport`] writing to `dir`.
+pub fn dump_connector(dir: &Path, inner: Connector) -> Connector {
+    let dir = PathBuf::from(dir);
+    Arc::new(move |hostname, if_name| {
+        Arc::new(DumpTransport::new(
+            inner(hostname, if_name),
+            &dir,
+            hostname,
+            if_name,
+        ))
+    })
+}
+
+/// Returns the connector wrapping transports created by `inner` with [`DumpTransport`] appending to `records`.
+pub fn memory_connector(records: DumpRecords, inner: Connector) -> Connector {
+    Arc::new(move |hostname, if_name| {
+        Arc::new(DumpTransport::memory(
+            inner(hostname, if_name),
+            Arc::clone(&records),
+            hostname,
+            if_name,
+        ))
+    })
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Replay
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Reads the records written by [`DumpTransport`] from `dir` in the order of the timestamps.
+pub fn read_dump(dir: &Path) -> Result<Vec<DumpRecord>, Error> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|x| x == "toml") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut records = Vec::new();
+    for path in paths {
+        let record = toml::from_str(&fs::read_to_string(&path)?)
+            .map_err(|x| format_err!("failed to read '{}': {}", path.display(), x))?;
+        records.push(record);
+    }
+    records.sort_by(|a: &DumpRecord, b| a.timestamp.total_cmp(&b.timestamp));
+    Ok(records)
+}
+
+/// Returns the connector creating [`ReplayTransport`] which serves the replies recorded in `dir` for each hostname.
+///
+/// The network interface is ignored, and failed exchanges in the dump time out.
+/// Each hostname has one transport shared by the clients, so that the recorded sequence of replies continues across
+/// them.
+pub fn replay_connector(dir: &Path) -> Result<Connector, Error> {
+    let mut packets: HashMap<String, Vec<Packets>> = HashMap::new();
+    for record in read_dump(dir)? {
+        if let Some(x) = record.packets()? {
+            packets.entry(record.target).or_default().push(x);
+        }
+    }
+    let transports: HashMap<String, Arc<dyn Transport>> = packets
+        .into_iter()
+        .map(|(hostname, x)| {
+            (
+                hostname,
+                Arc::new(ReplayTransport::new(x)) as Arc<dyn Transport>,
+            )
+        })
+        .collect();
+    let unknown: Arc<dyn Transport> = Arc::new(ReplayTransport::new(Vec::new()));
+    Ok(Arc::new(move |hostname, _| {
+        Arc::clone(transports.get(hostname).unwrap_or(&unknown))
+    }))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prosafe_switch::ProSafeSwitch;
+    use crate::transport::MockTransport;
+    use hex_literal::*;
+    use std::time::Duration;
+
+    const PORT_COUNT: [u8; 41] =
+        hex!("01020000000000000cc47a3a39a808bd436a1596000000804e534450000000006000000108ffff0000");
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("prosafe-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_record() {
+        let record = DumpRecord::new("switch1", "eth0", &[0x01, 0x01], Ok(&PORT_COUNT));
+        assert_eq!(record.reply, hex(&PORT_COUNT));
+        assert_eq!(
+            record.tlv,
+            vec![Tlv {
+                tag: String::from("0x6000"),
+                name: String::from("PortCount"),
+                length: 1,
+                value: String::from("08"),
+            }]
+        );
+        assert_eq!(record.decoded, vec![String::from("PortCount { count: 8 }")]);
+        assert_eq!(
+            record.packets().unwrap(),
+            Some((vec![0x01, 0x01], PORT_COUNT.to_vec()))
+        );
+
+        let err = format_err!("timed out");
+        let record = DumpRecord::new("switch1", "eth0", &[0x01, 0x01], Err(&err));
+        assert_eq!(record.error, "timed out");
+        assert_eq!(record.packets().unwrap(), None);
+    }
+
+    #[test]
+    fn test_dump_replay() {
+        let dir = temp_dir("dump");
+
+        let mock: Connector = Arc::new(|hostname, _| {
+            let hostname = String::from(hostname);
+            Arc::new(MockTransport::new(move |_| {
+                if hostname == "switch1" {
+                    Some(PORT_COUNT.to_vec())
+                } else {
+                    None
+                }
+            }))
+        });
+        let connector = dump_connector(&dir, mock);
+
+        let sw = ProSafeSwitch::with_connector("switch1", "eth0", Arc::clone(&connector));
+        assert_eq!(sw.port_count().unwrap(), PortCount { count: 8 });
+        let mut sw = ProSafeSwitch::with_connector("switch2", "eth0", connector);
+        sw.set_timeout(Duration::from_millis(10));
+        assert!(sw.port_count().is_err());
+
+        let records = read_dump(&dir).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records
+            .iter()
+            .any(|x| x.target == "switch2" && !x.error.is_empty()));
+
+        let connector = replay_connector(&dir).unwrap();
+        let mut sw = ProSafeSwitch::with_connector("switch1", "eth1", Arc::clone(&connector));
+        sw.set_timeout(Duration::from_millis(10));
+        assert_eq!(sw.port_count().unwrap(), PortCount { count: 8 });
+        assert!(sw.port_stat().is_err());
+        let mut sw = ProSafeSwitch::with_connector("switch2", "eth0", connector);
+        sw.set_timeout(Duration::from_millis(10));
+        assert!(sw.port_count().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_sequence() {
+        let dir = temp_dir("replay-sequence");
+
+        let mut second = PORT_COUNT;
+        second[36] = 5;
+        let count = Arc::new(AtomicUsize::new(0));
+        let mock: Connector = Arc::new(move |_, _| {
+            let count = Arc::clone(&count);
+            Arc::new(MockTransport::new(move |_| {
+                match count.fetch_add(1, Ordering::SeqCst) {
+                    0 => Some(PORT_COUNT.to_vec()),
+                    _ => Some(second.to_vec()),
+                }
+            }))
+        });
+        let connector = dump_connector(&dir, mock);
+        let sw = ProSafeSwitch::with_connector("switch1", "eth0", connector);
+        assert_eq!(sw.port_count().unwrap(), PortCount { count: 8 });
+        assert_eq!(sw.port_count().unwrap(), PortCount { count: 5 });
+
+        // The sequence continues across the clients created by the connector.
+        let connector = replay_connector(&dir).unwrap();
+        let sw = ProSafeSwitch::with_connector("switch1", "eth0", Arc::clone(&connector));
+        assert_eq!(sw.port_count().unwrap(), PortCount { count: 8 });
+        let sw = ProSafeSwitch::with_connector("switch1", "eth0", connector);
+        assert_eq!(sw.port_count().unwrap(), PortCount { count: 5 });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,37 @@
-use crate::prosafe_switch::{Link, ProSafeSwitch};
+use crate::collector::{
+    families_with_integers, CapabilitiesCache, Collector, Integers, Labels, ProbeResult,
+};
+use crate::config::Config;
+use crate::dump::{dump_connector, memory_connector, DumpRecord, DumpRecords};
+use crate::openmetrics::{CounterResets, Created, OpenMetricsEncoder, TextEncoder};
+use crate::prosafe_switch::{Link, PortStats, ProSafeSwitch, SpeedStats, SwitchFuture, SwitchInfo};
+use crate::transport::{Connector, UdpMux, UdpOptions};
+use failure::format_err;
 use failure::Error;
-use hyper::rt::{self, Future};
-use hyper::service::service_fn_ok;
-use hyper::{Body, Response, Server, Uri};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::{self, Shared};
+use futures::sync::mpsc;
+use hyper::rt::{self, Future, Stream};
+use hyper::service::service_fn;
+use hyper::{Body, Request, Response, Server, StatusCode, Uri};
 use lazy_static::lazy_static;
-use prometheus::{Encoder, GaugeVec, Opts, Registry, TextEncoder};
+use percent_encoding::percent_decode_str;
+#[cfg(target_os = "linux")]
+use prometheus::process_collector::ProcessCollector;
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Encoder, GaugeVec, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::lock::Lock;
+use tokio::timer::{Interval, Timeout};
 use url::form_urlencoded;
 
 // ---------------------------------------------------------------------------------------------------------------------
@@ -13,42 +39,52 @@ use url::form_urlencoded;
 // ---------------------------------------------------------------------------------------------------------------------
 
 lazy_static! {
-    static ref UP_OPT: Opts = Opts::new("prosafe_up", "The last query is successful.");
-    static ref RECEIVE_BYTES_OPT: Opts =
-        Opts::new("prosafe_receive_bytes_total", "Incoming transfer in bytes.");
-    static ref TRANSMIT_BYTES_OPT: Opts = Opts::new(
-        "prosafe_transmit_bytes_total",
-        "Outgoing transfer in bytes."
-    );
-    static ref ERROR_PACKETS_OPT: Opts =
-        Opts::new("prosafe_error_packets_total", "Transfer error in packets.");
-    static ref LINK_SPEED_OPT: Opts = Opts::new("prosafe_link_speed", "Link speed in Mbps.");
     static ref BUILD_INFO_OPT: Opts = Opts::new(
         "prosafe_build_info",
         "A metric with a constant '1' value labeled by version, revision and rustversion."
     );
+    static ref PROBES_OPT: Opts = Opts::new(
+        "prosafe_exporter_probes_total",
+        "Number of queries of switches by result."
+    );
+    static ref PROBE_DURATION_OPT: HistogramOpts = HistogramOpts::new(
+        "prosafe_exporter_probe_duration_seconds",
+        "Duration of queries of switches in seconds."
+    );
+    static ref LOCK_WAIT_OPT: HistogramOpts = HistogramOpts::new(
+        "prosafe_exporter_lock_wait_seconds",
+        "Time waiting for the lock of the NSDP port of the host in seconds."
+    );
+    static ref PROBES_IN_FLIGHT_OPT: Opts = Opts::new(
+        "prosafe_exporter_probes_in_flight",
+        "Number of queries of switches in flight."
+    );
 }
 
+/// Header in which Prometheus gives the scrape timeout in seconds.
+static SCRAPE_TIMEOUT_HEADER: &str = "X-Prometheus-Scrape-Timeout-Seconds";
+
+/// Path of the JSON API of the states of switches. A target is given by `/api/v1/switches/<target>`.
+static API_PATH: &str = "/api/v1/switches";
+
+/// Path of the live updates of a target by Server-Sent Events.
+static STREAM_PATH: &str = "/api/v1/stream";
+
+/// Path of the metrics of the exporter itself. `/metrics` serves them too if no static target is given.
+static TELEMETRY_PATH: &str = "/exporter/metrics";
+
 // ---------------------------------------------------------------------------------------------------------------------
 // Landing Page HTML
 // ---------------------------------------------------------------------------------------------------------------------
 
-static LANDING_PAGE: &'static str = r#"<html>
-<head><title>ProSAFE Exporter</title></head>
-<body>
-<h1>ProSAFE Exporter</h1>
-<form action="/probe">
-<label>Target:</label> <input type="text" name="target" placeholder="1.2.3.4:eth0"><br>
-<input type="submit" value="Submit">
-</form>
-</body>
-"#;
+/// Dashboard of the static targets and the targets added by `?target=`, which is refreshed from the JSON API.
+static LANDING_PAGE: &str = include_str!("dashboard.html");
 
 // ---------------------------------------------------------------------------------------------------------------------
 // Build info
 // ---------------------------------------------------------------------------------------------------------------------
 
-static VERSION: &'static str = env!("CARGO_PKG_VERSION");
+static VERSION: &str = env!("CARGO_PKG_VERSION");
 static GIT_REVISION: Option<&'static str> = option_env!("GIT_REVISION");
 static RUST_VERSION: Option<&'static str> = option_env!("RUST_VERSION");
 
@@ -56,210 +92,1405 @@ static RUST_VERSION: Option<&'static str> = option_env!("RUST_VERSION");
 // Exporter
 // ---------------------------------------------------------------------------------------------------------------------
 
-pub struct Exporter;
+/// HTTP server exposing metrics of switches.
+pub struct Exporter {
+    listen_address: String,
+    targets: Vec<String>,
+    verbose: bool,
+    timeout: Duration,
+    connector: Option<Connector>,
+    dump_dir: Option<PathBuf>,
+    config: Config,
+    poll_interval: Option<Duration>,
+    staleness: Duration,
+    probe_ttl: Duration,
+    timeout_offset: Duration,
+}
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+/// Future running the HTTP server.
+pub type ServerFuture = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+/// Results of the background polling by target name.
+type PollCache = Arc<Mutex<HashMap<String, PollEntry>>>;
+
+#[derive(Clone, Debug, Default)]
+struct PollEntry {
+    // The result of the last polling.
+    up: bool,
+    // The last successful result.
+    result: Option<ProbeResult>,
+    // The error of the last polling.
+    error: Option<String>,
+}
+
+/// Locks of the NSDP ports of the host.
+type PortLocks = Arc<Mutex<HashMap<u16, Lock<()>>>>;
+
+/// Sockets of the host shared by the targets with the same addresses.
+type Muxes = Arc<Mutex<HashMap<UdpOptions, Arc<UdpMux>>>>;
+
+/// Probes in flight or finished within the TTL by the settings of the target.
+type Flights = Arc<Mutex<HashMap<String, Flight>>>;
+
+struct Flight {
+    probe: Shared<SwitchFuture<ProbeResult>>,
+    // `None` while the probe is in flight.
+    finished: Option<Instant>,
+}
+
+/// Removes the flights finished before the TTL.
+fn sweep_flights(flights: &mut HashMap<String, Flight>, ttl: Duration) {
+    flights.retain(|_, x| x.finished.is_none_or(|finished| finished.elapsed() < ttl));
+}
+
+/// Port statistics of the targets by name, from which the rates of the next result are computed.
+type RateCache = Arc<Mutex<HashMap<String, RateEntry>>>;
+
+/// Age after which the port statistics of a target are dropped, so that ad-hoc targets don't stay forever.
+const RATE_RETENTION: Duration = Duration::from_secs(600);
+
+struct RateEntry {
+    time: SystemTime,
+    stats: PortStats,
+    // Rates between the previous result and `stats`.
+    rates: Vec<PortRate>,
+}
+
+/// Viewers of the streams by the target and the interval. Each stream is polled by one background task.
+type Streams = Arc<Mutex<HashMap<String, Vec<mpsc::UnboundedSender<String>>>>>;
+
+/// Results of the collectors with cache intervals by the switch and the collector.
+type CollectorCache = Arc<Mutex<HashMap<(String, Collector), CachedResult>>>;
+
+struct CachedResult {
+    // Time of the query.
+    time: Instant,
+    // Cache interval of the target which queried it.
+    interval: Duration,
+    result: ProbeResult,
+}
+
+/// Removes the results older than their cache intervals.
+fn sweep_collector_cache(cache: &mut HashMap<(String, Collector), CachedResult>) {
+    cache.retain(|_, x| x.time.elapsed() < x.interval);
+}
+
+/// State shared by all requests.
+#[derive(Clone)]
+struct Context {
+    verbose: bool,
+    timeout: Duration,
+    connector: Option<Connector>,
+    dump_dir: Option<PathBuf>,
+    recorder: Option<DumpRecords>,
+    config: Arc<Config>,
+    // Switches reply to the fixed port, so queries through the same port are serialized if a custom connector is
+    // given. The default connector shares a socket by `muxes` instead.
+    locks: PortLocks,
+    muxes: Muxes,
+    flights: Flights,
+    probe_ttl: Duration,
+    timeout_offset: Duration,
+    capabilities: CapabilitiesCache,
+    collector_cache: CollectorCache,
+    resets: Arc<CounterResets>,
+    rates: RateCache,
+    streams: Streams,
+    registry: Registry,
+    telemetry: Telemetry,
+    // Static targets probed through /metrics.
+    targets: Arc<Vec<(String, Target)>>,
+    poll_interval: Option<Duration>,
+    staleness: Duration,
+    polls: PollCache,
+}
+
+/// A switch given by `host:if_name` or by the name in the configuration file.
+#[derive(Clone, Debug)]
+struct Target {
+    host: String,
+    if_name: String,
+    udp: UdpOptions,
+    timeout: Duration,
+    retries: u32,
+    collectors: Vec<Collector>,
+    cache_interval: BTreeMap<Collector, Duration>,
+    labels: Labels,
+    // Interval of the background polling. `None` means the target is probed by each scrape.
+    interval: Option<Duration>,
+    staleness: Duration,
+    // Deadline of the whole probe given by the scrape timeout.
+    deadline: Option<Instant>,
+}
+
+/// State of a switch returned by the JSON API.
+#[derive(Serialize)]
+struct SwitchState {
+    target: String,
+    up: bool,
+    interface: String,
+    /// Last time the switch was polled successfully in seconds since UNIX epoch.
+    last_success: Option<f64>,
+    /// Message of the last failure in querying the switch.
+    error: Option<String>,
+    info: Option<SwitchInfo>,
+    port_count: Option<u8>,
+    port_stats: Option<PortStats>,
+    speed_stats: Option<SpeedStats>,
+    /// Rates since the previous result. Ports whose counters were reset are omitted.
+    rates: Vec<PortRate>,
+}
+
+/// Rates of the traffic counters of a port per second.
+#[derive(Clone, Debug, Serialize)]
+struct PortRate {
+    port_no: u8,
+    recv_bytes: f64,
+    send_bytes: f64,
+    error_pkts: f64,
+}
+
+/// Change of the link of a port between two results of a stream.
+#[derive(Serialize)]
+struct LinkChange {
+    port_no: u8,
+    link: Link,
+    previous: Link,
+}
+
+/// Response of /probe with `debug=1`.
+#[derive(Serialize)]
+struct DebugReport {
+    target: String,
+    interface: String,
+    up: bool,
+    duration: f64,
+    exchange: Vec<DumpRecord>,
+}
+
+impl Context {
+    fn connector(&self, target: &Target) -> Connector {
+        let mut connector = match self.connector {
+            Some(ref x) => Arc::clone(x),
+            None => {
+                let mut muxes = self.muxes.lock().unwrap();
+                let mux = muxes
+                    .entry(target.udp)
+                    .or_insert_with(|| Arc::new(UdpMux::new(target.udp)));
+                UdpMux::connector(mux)
+            }
+        };
+        if let Some(ref dir) = self.dump_dir {
+            connector = dump_connector(dir, connector);
+        }
+        if let Some(ref records) = self.recorder {
+            connector = memory_connector(Arc::clone(records), connector);
+        }
+        connector
+    }
+}
+
+impl Target {
+    fn resolve(target: &str, context: &Context) -> Option<Target> {
+        if let Some(x) = context.config.target(target) {
+            return Some(Target {
+                host: x.host.clone(),
+                if_name: x.interface.clone(),
+                udp: x.udp_options(),
+                timeout: x.timeout(),
+                retries: x.retries,
+                collectors: x.collectors.clone(),
+                cache_interval: x.cache_interval(),
+                labels: x.labels.clone().into_iter().collect(),
+                interval: x.interval().or(context.poll_interval),
+                staleness: x.staleness().unwrap_or(context.staleness),
+                deadline: None,
+            });
+        }
+
+        let mut target = target.splitn(2, ':');
+        match (target.next(), target.next()) {
+            (Some(host), Some(if_name)) => Some(Target {
+                host: String::from(host),
+                if_name: String::from(if_name),
+                udp: UdpOptions::default(),
+                timeout: context.timeout,
+                retries: 0,
+                collectors: Collector::ALL.to_vec(),
+                cache_interval: BTreeMap::new(),
+                labels: Vec::new(),
+                interval: context.poll_interval,
+                staleness: context.staleness,
+                deadline: None,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the key identifying probes giving the same result.
+    fn key(&self) -> String {
+        format!(
+            "{}:{:?}:{:?}:{}:{:?}:{:?}",
+            self.switch_key(),
+            self.udp,
+            self.timeout,
+            self.retries,
+            self.collectors,
+            self.cache_interval
+        )
+    }
+
+    /// Returns the key identifying the switch.
+    fn switch_key(&self) -> String {
+        format!("{}:{}:{:?}", self.host, self.if_name, self.udp)
+    }
+}
 
 impl Exporter {
-    pub fn start(listen_address: &str, target: Option<String>, verbose: bool) -> Result<(), Error> {
-        let addr = format!("0.0.0.0{}", listen_address).parse()?;
+    /// Creates an exporter listening on `listen_address` ( e.g. `:9493` or `127.0.0.1:9493` ).
+    pub fn new(listen_address: &str) -> Self {
+        Exporter {
+            listen_address: String::from(listen_address),
+            targets: Vec::new(),
+            verbose: false,
+            timeout: Duration::new(1, 0),
+            connector: None,
+            dump_dir: None,
+            config: Config::default(),
+            poll_interval: None,
+            staleness: Duration::from_secs(60),
+            probe_ttl: Duration::from_secs(0),
+            timeout_offset: Duration::from_millis(500),
+        }
+    }
+
+    /// Sets the static target probed through /metrics. It is `host:if_name` or the name of a configured target.
+    pub fn target(mut self, target: Option<String>) -> Self {
+        self.targets = target.into_iter().collect();
+        self
+    }
+
+    /// Sets the static targets probed concurrently through /metrics.
+    ///
+    /// If no target is given, all targets in the configuration file are probed.
+    pub fn targets(mut self, targets: Vec<String>) -> Self {
+        self.targets = targets;
+        self
+    }
+
+    /// Shows verbose message.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Sets the timeout of each query to switches. The default is 1 second.
+    ///
+    /// Targets in the configuration file have their own timeouts.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the connector creating transports to switches.
+    ///
+    /// The default is [`UdpMux::connector`] sharing a socket between the targets with the same addresses.
+    /// Queries through a custom connector are serialized by the NSDP port of the host.
+    pub fn connector(mut self, connector: Connector) -> Self {
+        self.connector = Some(connector);
+        self
+    }
+
+    /// Sets the directory to which every exchange with switches is written.
+    pub fn dump_dir(mut self, dump_dir: Option<PathBuf>) -> Self {
+        self.dump_dir = dump_dir;
+        self
+    }
+
+    /// Sets the configuration file describing named targets.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Polls the static targets in the background at `interval`, and serves scrapes from the last result.
+    ///
+    /// Targets in the configuration file with their own intervals are polled even if it is `None`.
+    /// A zero interval is rejected by `bind`.
+    pub fn poll_interval(mut self, interval: Option<Duration>) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Sets the age of the polled result after which the metrics except `prosafe_up` and
+    /// `prosafe_last_success_timestamp_seconds` are dropped. The default is 60 seconds.
+    pub fn staleness(mut self, staleness: Duration) -> Self {
+        self.staleness = staleness;
+        self
+    }
+
+    /// Sets how long the result of a probe is reused by the following probes of the same target.
+    ///
+    /// Concurrent probes of the same target always share one query even if it is zero, the default.
+    pub fn probe_ttl(mut self, ttl: Duration) -> Self {
+        self.probe_ttl = ttl;
+        self
+    }
+
+    /// Sets the time subtracted from the scrape timeout given by Prometheus to leave room for the response.
+    /// The default is 500 milliseconds.
+    ///
+    /// The queries of a scrape stop at the scrape timeout less the offset, and the collected metrics are returned.
+    pub fn timeout_offset(mut self, offset: Duration) -> Self {
+        self.timeout_offset = offset;
+        self
+    }
+
+    /// Runs the HTTP server until it fails.
+    pub fn start(self) -> Result<(), Error> {
+        let verbose = self.verbose;
+        let (addr, server) = self.bind()?;
 
         if verbose {
             println!("Server started: {:?}", addr);
         }
 
-        let mutex = Arc::new(Mutex::new(()));
+        rt::run(server);
+
+        Ok(())
+    }
+
+    /// Binds the listen address, and returns the bound address and the future running the HTTP server.
+    pub fn bind(self) -> Result<(SocketAddr, ServerFuture), Error> {
+        let addr = if self.listen_address.starts_with(':') {
+            format!("0.0.0.0{}", self.listen_address)
+        } else {
+            self.listen_address.clone()
+        };
+        let addr = addr.parse()?;
+        if self.poll_interval == Some(Duration::from_secs(0)) {
+            return Err(format_err!("poll interval must be positive"));
+        }
+
+        let mut context = Context {
+            verbose: self.verbose,
+            timeout: self.timeout,
+            connector: self.connector,
+            dump_dir: self.dump_dir,
+            recorder: None,
+            config: Arc::new(self.config),
+            locks: Arc::new(Mutex::new(HashMap::new())),
+            muxes: Arc::new(Mutex::new(HashMap::new())),
+            flights: Arc::new(Mutex::new(HashMap::new())),
+            probe_ttl: self.probe_ttl,
+            timeout_offset: self.timeout_offset,
+            capabilities: Arc::new(Mutex::new(HashMap::new())),
+            collector_cache: Arc::new(Mutex::new(HashMap::new())),
+            resets: Arc::new(CounterResets::new()),
+            rates: Arc::new(Mutex::new(HashMap::new())),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            registry: Exporter::registry(),
+            telemetry: Telemetry::new(),
+            targets: Arc::new(Vec::new()),
+            poll_interval: self.poll_interval,
+            staleness: self.staleness,
+            polls: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        let names = if self.targets.is_empty() {
+            context
+                .config
+                .targets
+                .iter()
+                .map(|x| x.name.clone())
+                .collect()
+        } else {
+            self.targets
+        };
+        let mut targets = Vec::new();
+        for name in names {
+            let target = Target::resolve(&name, &context)
+                .ok_or_else(|| format_err!("unknown target '{}'", name))?;
+            targets.push((name, target));
+        }
+        context.targets = Arc::new(targets);
+
+        let pollers: Vec<_> = context
+            .targets
+            .iter()
+            .filter_map(|(name, target)| {
+                target
+                    .interval
+                    .map(|x| Exporter::poll(name.clone(), target.clone(), x, context.clone()))
+            })
+            .collect();
 
         let service = move || {
-            let mutex = Arc::clone(&mutex);
-            let target = target.clone();
-            service_fn_ok(move |req| {
-                let mutex = Arc::clone(&mutex);
+            let context = context.clone();
+            service_fn(move |req| -> ResponseFuture {
+                let context = context.clone();
                 let uri = req.uri();
+                let scrape = Scrape::new(&req, &context);
 
-                let static_uri = if let Some(ref target) = target {
-                    let uri = format!("/probe?target={}", target).parse::<Uri>();
-                    if let Ok(uri) = uri {
-                        Some(uri)
-                    } else {
-                        None
+                if uri.path() == "/probe" {
+                    Exporter::probe(uri, scrape, context)
+                } else if uri.path() == "/metrics" && !context.targets.is_empty() {
+                    Exporter::metrics(scrape, context)
+                } else if uri.path() == STREAM_PATH {
+                    Exporter::stream(uri, context)
+                } else if uri.path() == API_PATH {
+                    Exporter::api_switches(context)
+                } else if let Some(target) = uri.path().strip_prefix(API_PATH) {
+                    match target.strip_prefix('/') {
+                        Some(target) if !target.is_empty() => {
+                            let target = percent_decode_str(target).decode_utf8_lossy();
+                            Exporter::api_switch(target.into_owned(), context)
+                        }
+                        _ => Box::new(future::ok(landing_page())),
                     }
+                } else if uri.path() == "/metrics" || uri.path() == TELEMETRY_PATH {
+                    Box::new(future::ok(context.telemetry.render(&scrape)))
                 } else {
-                    None
+                    Box::new(future::ok(landing_page()))
+                }
+            })
+        };
+
+        let server = Server::try_bind(&addr)?.serve(service);
+        let addr = server.local_addr();
+        let server = server.map_err(|e| eprintln!("Server error: {}", e));
+        let server = future::lazy(move || {
+            for poller in pollers {
+                rt::spawn(poller);
+            }
+            server
+        });
+
+        Ok((addr, Box::new(server)))
+    }
+
+    fn probe(uri: &Uri, scrape: Scrape, context: Context) -> ResponseFuture {
+        let mut target = None;
+        let mut debug = false;
+        let mut collectors = Vec::new();
+        if let Some(query) = uri.query() {
+            let query = form_urlencoded::parse(query.as_bytes());
+            for (k, v) in query {
+                if k == "target" {
+                    target = Some(String::from(v));
+                } else if k == "debug" && (v == "1" || v == "true") {
+                    debug = true;
+                } else if k == "collect[]" || k == "collect" {
+                    match v.parse::<Collector>() {
+                        Ok(x) if !collectors.contains(&x) => collectors.push(x),
+                        Ok(_) => (),
+                        Err(x) => {
+                            let response = Response::builder()
+                                .status(StatusCode::BAD_REQUEST)
+                                .body(Body::from(format!("{}\n", x)))
+                                .unwrap();
+                            return Box::new(future::ok(response));
+                        }
+                    }
+                }
+            }
+        }
+
+        let (name, mut target) =
+            match target.and_then(|x| Target::resolve(&x, &context).map(|t| (x, t))) {
+                Some(x) => x,
+                None => return Box::new(future::ok(Exporter::render(&[], "", &scrape, &context))),
+            };
+        if !collectors.is_empty() {
+            target.collectors = collectors;
+        }
+        target.deadline = scrape.deadline;
+
+        if debug {
+            return Exporter::debug(name, target, context);
+        }
+
+        let labels = target.labels.clone();
+        Box::new(
+            Exporter::collect(&name, target, context.clone()).then(move |x| {
+                let result = x.unwrap_or_default();
+                Ok(Exporter::render(
+                    &[(result, labels)],
+                    &name,
+                    &scrape,
+                    &context,
+                ))
+            }),
+        )
+    }
+
+    /// Probes the static targets concurrently, and renders them with `instance` and `target` labels.
+    fn metrics(scrape: Scrape, context: Context) -> ResponseFuture {
+        let probes: Vec<_> = context
+            .targets
+            .iter()
+            .map(|(name, target)| {
+                let mut labels = vec![
+                    (String::from("instance"), name.clone()),
+                    (String::from("target"), name.clone()),
+                ];
+                labels.extend(target.labels.iter().cloned());
+                let target = Target {
+                    deadline: scrape.deadline,
+                    ..target.clone()
                 };
+                Exporter::collect(name, target, context.clone())
+                    .then(move |x| Ok((x.unwrap_or_default(), labels)))
+            })
+            .collect();
 
-                if uri.path() == "/probe" {
-                    Exporter::probe(uri, false, verbose, mutex)
-                } else if uri.path() == "/metrics" {
-                    if let Some(static_uri) = static_uri {
-                        Exporter::probe(&static_uri, true, verbose, mutex)
-                    } else {
-                        Response::new(Body::from(LANDING_PAGE))
+        Box::new(
+            future::join_all(probes)
+                .map(move |results| Exporter::render(&results, "", &scrape, &context)),
+        )
+    }
+
+    /// Returns the states of the static targets and the other targets in the configuration file in JSON.
+    ///
+    /// The polled targets are served from the last result like `/metrics`, and the others are probed.
+    fn api_switches(context: Context) -> ResponseFuture {
+        let mut targets = context.targets.as_ref().clone();
+        for x in &context.config.targets {
+            if targets.iter().all(|(name, _)| *name != x.name) {
+                if let Some(target) = Target::resolve(&x.name, &context) {
+                    targets.push((x.name.clone(), target));
+                }
+            }
+        }
+        let probes: Vec<_> = targets
+            .into_iter()
+            .map(|(name, target)| {
+                let rates = Arc::clone(&context.rates);
+                Exporter::collect(&name, target, context.clone())
+                    .then(move |x| Ok(SwitchState::new(name, probe_result(x), &rates)))
+            })
+            .collect();
+
+        Box::new(future::join_all(probes).map(|states| json(StatusCode::OK, &states)))
+    }
+
+    /// Returns the state of the target in JSON. The target is given like `/probe`.
+    fn api_switch(name: String, context: Context) -> ResponseFuture {
+        let target = match Target::resolve(&name, &context) {
+            Some(x) => x,
+            None => {
+                let error = ApiError {
+                    error: format!("unknown target '{}'", name),
+                };
+                return Box::new(future::ok(json(StatusCode::NOT_FOUND, &error)));
+            }
+        };
+
+        let rates = Arc::clone(&context.rates);
+        Box::new(Exporter::collect(&name, target, context).then(move |x| {
+            let state = SwitchState::new(name, probe_result(x), &rates);
+            Ok(json(StatusCode::OK, &state))
+        }))
+    }
+
+    /// Streams the states of the target by Server-Sent Events at the interval in milliseconds.
+    ///
+    /// The viewers of the same target and interval share one background task polling the switch.
+    fn stream(uri: &Uri, context: Context) -> ResponseFuture {
+        let mut target = None;
+        let mut interval = Some(Duration::from_secs(1));
+        if let Some(query) = uri.query() {
+            let query = form_urlencoded::parse(query.as_bytes());
+            for (k, v) in query {
+                if k == "target" {
+                    target = Some(String::from(v));
+                } else if k == "interval" {
+                    interval = v.parse::<u64>().ok().map(Duration::from_millis);
+                }
+            }
+        }
+
+        let interval = match interval {
+            Some(x) if x >= Duration::from_millis(100) => x,
+            _ => {
+                let error = ApiError {
+                    error: String::from("interval must be milliseconds of at least 100"),
+                };
+                return Box::new(future::ok(json(StatusCode::BAD_REQUEST, &error)));
+            }
+        };
+        let (name, target) =
+            match target.and_then(|x| Target::resolve(&x, &context).map(|t| (x, t))) {
+                Some(x) => x,
+                None => {
+                    let error = ApiError {
+                        error: String::from("unknown target"),
+                    };
+                    return Box::new(future::ok(json(StatusCode::NOT_FOUND, &error)));
+                }
+            };
+
+        let (tx, rx) = mpsc::unbounded();
+        let key = format!("{}:{:?}", target.key(), interval);
+        let mut streams = context.streams.lock().unwrap();
+        match streams.get_mut(&key) {
+            Some(viewers) => viewers.push(tx),
+            None => {
+                streams.insert(key.clone(), vec![tx]);
+                let poll = Exporter::poll_stream(key, name, target, interval, context.clone());
+                rt::spawn(poll);
+            }
+        }
+
+        let body = Body::wrap_stream(rx.map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe)));
+        Box::new(future::ok(
+            Response::builder()
+                .header("Content-Type", "text/event-stream")
+                .header("Cache-Control", "no-cache")
+                .body(body)
+                .unwrap(),
+        ))
+    }
+
+    /// Polls the target at `interval`, and sends the states to the viewers until all of them are gone.
+    fn poll_stream(
+        key: String,
+        name: String,
+        target: Target,
+        interval: Duration,
+        context: Context,
+    ) -> impl Future<Item = (), Error = ()> + Send {
+        // The rates are computed between the results of this stream.
+        let rates: RateCache = Arc::new(Mutex::new(HashMap::new()));
+        Interval::new(Instant::now(), interval)
+            .map_err(|x| eprintln!("Timer error: {}", x))
+            .fold(None, move |links: Option<SpeedStats>, _| {
+                let key = key.clone();
+                let name = name.clone();
+                let rates = Arc::clone(&rates);
+                let streams = Arc::clone(&context.streams);
+                Exporter::collect(&name, target.clone(), context.clone()).then(move |x| {
+                    let state = SwitchState::new(name, probe_result(x), &rates);
+                    let mut events = link_changes(links.as_ref(), state.speed_stats.as_ref())
+                        .iter()
+                        .map(|x| event("link", x))
+                        .collect::<Vec<_>>();
+                    events.push(event("state", &state));
+
+                    let mut streams = streams.lock().unwrap();
+                    let viewers = streams.entry(key.clone()).or_default();
+                    viewers.retain(|x| events.iter().all(|e| x.unbounded_send(e.clone()).is_ok()));
+                    if viewers.is_empty() {
+                        streams.remove(&key);
+                        return Err(());
+                    }
+                    // The links are kept while the switch is down.
+                    Ok(state.speed_stats.or(links))
+                })
+            })
+            .map(|_| ())
+    }
+
+    /// Returns the polled result of the static target, or probes the target.
+    fn collect(name: &str, target: Target, context: Context) -> SwitchFuture<ProbeResult> {
+        let polled = target.interval.is_some() && context.targets.iter().any(|(x, _)| x == name);
+        if !polled {
+            // A probe joining a query with a later deadline gives up at the scrape timeout.
+            let limit = target.deadline.map(|x| x + context.timeout_offset);
+            let probe = Exporter::query_coalesced(target, context);
+            return match limit {
+                Some(x) => Box::new(Timeout::new_at(probe, x).map_err(|x| format_err!("{}", x))),
+                None => probe,
+            };
+        }
+
+        let entry = context
+            .polls
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .unwrap_or_default();
+        let result = match entry.result {
+            Some(result) => {
+                let fresh = result
+                    .last_success
+                    .and_then(|x| x.elapsed().ok())
+                    .is_some_and(|x| x <= target.staleness);
+                if fresh {
+                    ProbeResult {
+                        up: entry.up,
+                        error: entry.error,
+                        ..result
                     }
                 } else {
-                    Response::new(Body::from(LANDING_PAGE))
+                    ProbeResult {
+                        last_success: result.last_success,
+                        error: entry.error,
+                        ..Default::default()
+                    }
                 }
-            })
+            }
+            None => ProbeResult {
+                error: entry.error,
+                ..Default::default()
+            },
         };
+        Box::new(future::ok(result))
+    }
 
-        let server = Server::bind(&addr)
-            .serve(service)
-            .map_err(|e| eprintln!("Server error: {}", e));
+    /// Polls the target at `interval` until the server stops.
+    fn poll(
+        name: String,
+        target: Target,
+        interval: Duration,
+        context: Context,
+    ) -> impl Future<Item = (), Error = ()> + Send {
+        Interval::new(Instant::now(), interval)
+            .map_err(|x| eprintln!("Timer error: {}", x))
+            .for_each(move |_| {
+                let name = name.clone();
+                let polls = Arc::clone(&context.polls);
+                Exporter::query_locked(target.clone(), context.clone()).then(move |x| {
+                    let result = probe_result(x);
+                    let mut polls = polls.lock().unwrap();
+                    let entry = polls.entry(name).or_default();
+                    entry.up = result.up;
+                    entry.error = result.error.clone();
+                    if result.up {
+                        entry.result = Some(ProbeResult {
+                            last_success: Some(SystemTime::now()),
+                            ..result
+                        });
+                    }
+                    Ok(())
+                })
+            })
+    }
 
-        rt::run(server);
+    /// Probes the target recording all exchanges, and returns them with the TLVs and the decoded values in TOML.
+    fn debug(name: String, target: Target, mut context: Context) -> ResponseFuture {
+        let records: DumpRecords = Arc::new(Mutex::new(Vec::new()));
+        context.recorder = Some(Arc::clone(&records));
 
-        Ok(())
+        let start = Instant::now();
+        Box::new(Exporter::query_locked(target, context).then(move |x| {
+            let result = x.unwrap_or_default();
+            let report = DebugReport {
+                target: name,
+                interface: result.interface,
+                up: result.up,
+                duration: start.elapsed().as_secs_f64(),
+                exchange: records.lock().unwrap().clone(),
+            };
+            let body = toml::to_string(&report).unwrap_or_else(|x| format!("{}", x));
+            Ok(Response::builder()
+                .header("Content-Type", "text/plain; charset=utf-8")
+                .body(Body::from(body))
+                .unwrap())
+        }))
     }
 
-    fn probe(
-        uri: &Uri,
-        instance_label: bool,
-        verbose: bool,
-        mutex: Arc<Mutex<()>>,
-    ) -> Response<Body> {
-        let registry = Registry::new();
+    /// Probes the target sharing the query with the concurrent probes and the result within the TTL.
+    fn query_coalesced(target: Target, context: Context) -> SwitchFuture<ProbeResult> {
+        let key = target.key();
+        let mut flights = context.flights.lock().unwrap();
+        sweep_flights(&mut flights, context.probe_ttl);
+        if !flights.contains_key(&key) {
+            let ttl = context.probe_ttl;
+            let finish_flights = Arc::clone(&context.flights);
+            let finish_key = key.clone();
+            let probe: SwitchFuture<ProbeResult> = Box::new(
+                Exporter::query_locked(target, context.clone()).then(move |x| {
+                    // The flight is never replaced while in flight, so the entry is this probe.
+                    let mut flights = finish_flights.lock().unwrap();
+                    if ttl == Duration::from_secs(0) {
+                        flights.remove(&finish_key);
+                    } else if let Some(flight) = flights.get_mut(&finish_key) {
+                        flight.finished = Some(Instant::now());
+                    }
+                    x
+                }),
+            );
+            flights.insert(
+                key.clone(),
+                Flight {
+                    probe: probe.shared(),
+                    finished: None,
+                },
+            );
+        }
 
-        let build_info = GaugeVec::new(
-            BUILD_INFO_OPT.clone(),
-            &["version", "revision", "rustversion"],
+        let probe = flights[&key].probe.clone();
+        Box::new(
+            probe
+                .map(|x| (*x).clone())
+                .map_err(|x| format_err!("{}", *x)),
         )
-        .unwrap();
+    }
 
-        let up_label = if instance_label {
-            vec!["instance"]
+    /// Probes the target. Queries through a custom connector are serialized by the NSDP port of the host.
+    fn query_locked(target: Target, context: Context) -> SwitchFuture<ProbeResult> {
+        let telemetry = context.telemetry.clone();
+        let in_flight = InFlight::new(&telemetry.probes_in_flight);
+        let start = Instant::now();
+
+        let query = if context.connector.is_none() {
+            Exporter::query(&target, &context)
         } else {
-            vec![]
+            let mut lock = context
+                .locks
+                .lock()
+                .unwrap()
+                .entry(target.udp.host_port)
+                .or_insert_with(|| Lock::new(()))
+                .clone();
+            let lock_wait = telemetry.lock_wait.clone();
+            Box::new(
+                future::poll_fn(move || Ok(lock.poll_lock())).and_then(move |guard| {
+                    lock_wait.observe(start.elapsed().as_secs_f64());
+                    Exporter::query(&target, &context).then(move |x| {
+                        drop(guard);
+                        x
+                    })
+                }),
+            )
         };
 
-        let label = if instance_label {
-            vec!["instance", "port"]
+        Box::new(query.then(move |x| {
+            drop(in_flight);
+            let success = x.as_ref().map(|x| x.up).unwrap_or(false);
+            telemetry
+                .probes
+                .with_label_values(&[if success { "success" } else { "failure" }])
+                .inc();
+            telemetry
+                .probe_duration
+                .observe(start.elapsed().as_secs_f64());
+            x
+        }))
+    }
+
+    fn switch(host: &str, if_name: &str, target: &Target, connector: &Connector) -> ProSafeSwitch {
+        let mut sw = ProSafeSwitch::with_connector(host, if_name, Arc::clone(connector));
+        sw.set_timeout(target.timeout);
+        sw.set_retries(target.retries);
+        sw
+    }
+
+    fn query(target: &Target, context: &Context) -> SwitchFuture<ProbeResult> {
+        let host = target.host.clone();
+        let if_name = target.if_name.clone();
+
+        if context.verbose {
+            println!("Access to switch: {} though {}", host, if_name);
+        }
+
+        let start = Instant::now();
+        let connector = context.connector(target);
+
+        let iface: SwitchFuture<String> = if if_name == "*" {
+            let sw = Exporter::switch(&host, &if_name, target, &connector);
+            let host = host.clone();
+            let deadline = match target.deadline {
+                Some(x) => x.min(sw.deadline()),
+                None => sw.deadline(),
+            };
+            Box::new(sw.find_iface_async(deadline).or_else(move |_| {
+                eprintln!("Fail to find accessible network interface to {}", host);
+                Ok(if_name)
+            }))
         } else {
-            vec!["port"]
+            Box::new(future::ok(if_name))
+        };
+
+        // The collectors queried within their cache intervals are not run.
+        let switch_key = target.switch_key();
+        let cached: Vec<_> = {
+            let cache = context.collector_cache.lock().unwrap();
+            target
+                .collectors
+                .iter()
+                .filter_map(|x| {
+                    let interval = target.cache_interval.get(x)?;
+                    let cached = cache.get(&(switch_key.clone(), *x))?;
+                    if cached.time.elapsed() < *interval {
+                        Some((*x, cached.result.clone()))
+                    } else {
+                        None
+                    }
+                })
+                .collect()
         };
+        let collectors: Vec<_> = target
+            .collectors
+            .iter()
+            .copied()
+            .filter(|x| !cached.iter().any(|(y, _)| x == y))
+            .collect();
+
+        let deadline = target.deadline;
+        let capabilities = Arc::clone(&context.capabilities);
+        let collector_cache = Arc::clone(&context.collector_cache);
+        let target = target.clone();
+        Box::new(iface.and_then(move |if_name| {
+            let sw = Exporter::switch(&host, &if_name, &target, &connector);
+            ProbeResult::query_async(&sw, &collectors, &capabilities, deadline).map(move |result| {
+                // The search of the interface is included.
+                let mut result = ProbeResult {
+                    interface: if_name,
+                    duration: Some(start.elapsed()),
+                    ..result
+                };
 
-        let up = GaugeVec::new(UP_OPT.clone(), &up_label).unwrap();
-        let receive_bytes = GaugeVec::new(RECEIVE_BYTES_OPT.clone(), &label).unwrap();
-        let transmit_bytes = GaugeVec::new(TRANSMIT_BYTES_OPT.clone(), &label).unwrap();
-        let error_packets = GaugeVec::new(ERROR_PACKETS_OPT.clone(), &label).unwrap();
-        let link_speed = GaugeVec::new(LINK_SPEED_OPT.clone(), &label).unwrap();
+                let mut cache = collector_cache.lock().unwrap();
+                sweep_collector_cache(&mut cache);
+                for x in &result.collectors {
+                    if let Some(interval) = target
+                        .cache_interval
+                        .get(&x.collector)
+                        .filter(|_| x.success)
+                    {
+                        let mut entry = ProbeResult::default();
+                        entry.copy_collector(x.collector, &result);
+                        let entry = CachedResult {
+                            time: Instant::now(),
+                            interval: *interval,
+                            result: entry,
+                        };
+                        cache.insert((switch_key.clone(), x.collector), entry);
+                    }
+                }
 
+                for (collector, x) in &cached {
+                    result.copy_collector(*collector, x);
+                }
+                // A probe served only from the cache is up, because only successful results are cached.
+                if collectors.is_empty() && !cached.is_empty() {
+                    result.up = true;
+                }
+                result
+            })
+        }))
+    }
+
+    /// Renders the results of switches, each with the labels added to its metrics.
+    ///
+    /// `scope` tells apart the counters of the switches which have the same labels, like the target of `/probe`.
+    fn render(
+        results: &[(ProbeResult, Labels)],
+        scope: &str,
+        scrape: &Scrape,
+        context: &Context,
+    ) -> Response<Body> {
+        let (switch_familys, integers) = families_with_integers(results);
+        let created = context.resets.update(scope, &switch_familys);
+
+        let mut metric_familys = context.registry.gather();
+        metric_familys.extend(switch_familys);
+        metric_familys.sort_by(|x, y| x.get_name().cmp(y.get_name()));
+        scrape.respond(&metric_familys, created, integers)
+    }
+
+    /// Returns the registry of the metrics of the exporter itself.
+    fn registry() -> Registry {
+        let registry = Registry::new();
+
+        let build_info = GaugeVec::new(
+            BUILD_INFO_OPT.clone(),
+            &["version", "revision", "rustversion"],
+        )
+        .unwrap();
         let _ = registry.register(Box::new(build_info.clone()));
-        let _ = registry.register(Box::new(up.clone()));
-        let _ = registry.register(Box::new(receive_bytes.clone()));
-        let _ = registry.register(Box::new(transmit_bytes.clone()));
-        let _ = registry.register(Box::new(error_packets.clone()));
-        let _ = registry.register(Box::new(link_speed.clone()));
 
         let git_revision = GIT_REVISION.unwrap_or("");
         let rust_version = RUST_VERSION.unwrap_or("");
         build_info
-            .with_label_values(&[&VERSION, &git_revision, &rust_version])
+            .with_label_values(&[VERSION, git_revision, rust_version])
             .set(1.0);
 
-        if let Some(query) = uri.query() {
-            let mut target = None;
-            let query = form_urlencoded::parse(query.as_bytes());
-            for (k, v) in query {
-                if k == "target" && v.contains(':') {
-                    target = Some(v);
-                }
-            }
-            if let Some(target) = target {
-                let instance_string = String::from(target.clone());
-                let label = if instance_label {
-                    vec![instance_string.as_str()]
-                } else {
-                    vec![]
-                };
+        registry
+    }
+}
 
-                let target: Vec<&str> = target.split(':').collect();
+// ---------------------------------------------------------------------------------------------------------------------
+// API
+// ---------------------------------------------------------------------------------------------------------------------
 
-                let host = &target[0];
-                let if_name = &target[1];
+/// Error returned by the JSON API.
+#[derive(Serialize)]
+struct ApiError {
+    error: String,
+}
 
-                if verbose {
-                    println!("Access to switch: {} though {}", host, if_name);
-                }
+impl SwitchState {
+    fn new(target: String, result: ProbeResult, rates: &RateCache) -> Self {
+        // Polled results are sampled at the last success, and the others now.
+        let time = result.last_success.unwrap_or_else(SystemTime::now);
+        let rates = match result.port_stats {
+            Some(ref stats) => port_rates(rates, &target, time, stats),
+            None => Vec::new(),
+        };
+        SwitchState {
+            target,
+            up: result.up,
+            interface: result.interface,
+            last_success: result
+                .last_success
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .map(|x| x.as_secs_f64()),
+            error: result.error,
+            info: result.info,
+            port_count: result.port_count.map(|x| x.count),
+            port_stats: result.port_stats,
+            speed_stats: result.speed_stats,
+            rates,
+        }
+    }
+}
 
-                {
-                    let _guard = mutex.lock();
-
-                    let if_name = if if_name == &"*" {
-                        let sw = ProSafeSwitch::new(&host, &if_name);
-                        match sw.find_iface() {
-                            Ok(iface) => iface,
-                            Err(_) => {
-                                eprintln!("Fail to find accessible network interface to {}", host);
-                                String::from(*if_name)
-                            }
-                        }
-                    } else {
-                        String::from(*if_name)
-                    };
+/// Returns the dashboard, which is the response of any unknown path.
+fn landing_page() -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(LANDING_PAGE))
+        .unwrap()
+}
 
-                    let sw = ProSafeSwitch::new(&host, &if_name);
-                    match sw.port_stat() {
-                        Ok(stats) => {
-                            for s in stats.stats {
-                                let port = format!("{}", s.port_no);
-                                let label = if instance_label {
-                                    vec![instance_string.as_str(), port.as_str()]
-                                } else {
-                                    vec![port.as_str()]
-                                };
-
-                                receive_bytes
-                                    .with_label_values(&label)
-                                    .set(s.recv_bytes as f64);
-                                transmit_bytes
-                                    .with_label_values(&label)
-                                    .set(s.send_bytes as f64);
-                                error_packets
-                                    .with_label_values(&label)
-                                    .set(s.error_pkts as f64);
-                            }
-
-                            up.with_label_values(&label).set(1.0);
-                        }
-                        Err(x) => {
-                            up.with_label_values(&label).set(0.0);
-                            eprintln!("Fail to access: {}", x);
-                        }
-                    }
-                    match sw.speed_stat() {
-                        Ok(stats) => {
-                            for s in stats.stats {
-                                let port = format!("{}", s.port_no);
-                                let label = if instance_label {
-                                    vec![instance_string.as_str(), port.as_str()]
-                                } else {
-                                    vec![port.as_str()]
-                                };
-
-                                let speed = match s.link {
-                                    Link::None => 0,
-                                    Link::Speed10Mbps => 10,
-                                    Link::Speed100Mbps => 100,
-                                    Link::Speed1Gbps => 1000,
-                                    Link::Speed10Gbps => 10000,
-                                    Link::Unknown => 0,
-                                };
-                                link_speed.with_label_values(&label).set(f64::from(speed));
-                            }
-                        }
-                        Err(x) => {
-                            eprintln!("Fail to access: {}", x);
-                        }
-                    }
-                }
+/// Returns the result of a probe, or an empty result with the message of the failure.
+fn probe_result(x: Result<ProbeResult, Error>) -> ProbeResult {
+    x.unwrap_or_else(|x| ProbeResult {
+        error: Some(format!("{}", x)),
+        ..Default::default()
+    })
+}
+
+/// Returns the rates between the previous statistics of the target and `stats` sampled at `time`.
+///
+/// The same sample, like a polled result served twice, returns the same rates.
+fn port_rates(
+    rates: &RateCache,
+    target: &str,
+    time: SystemTime,
+    stats: &PortStats,
+) -> Vec<PortRate> {
+    let mut rates = rates.lock().unwrap();
+    let prev = rates.get(target);
+    if let Some(prev) = prev.filter(|x| x.time == time) {
+        return prev.rates.clone();
+    }
+
+    let mut result = Vec::new();
+    if let Some(prev) = prev {
+        let secs = time
+            .duration_since(prev.time)
+            .map(|x| x.as_secs_f64())
+            .unwrap_or(0.0);
+        let ports = if secs > 0.0 { &stats.stats[..] } else { &[] };
+        for x in ports {
+            let y = match prev.stats.stats.iter().find(|y| y.port_no == x.port_no) {
+                Some(y) => y,
+                None => continue,
+            };
+            let reset = x.recv_bytes < y.recv_bytes
+                || x.send_bytes < y.send_bytes
+                || x.error_pkts < y.error_pkts;
+            if !reset {
+                result.push(PortRate {
+                    port_no: x.port_no,
+                    recv_bytes: (x.recv_bytes - y.recv_bytes) as f64 / secs,
+                    send_bytes: (x.send_bytes - y.send_bytes) as f64 / secs,
+                    error_pkts: (x.error_pkts - y.error_pkts) as f64 / secs,
+                });
             }
         }
+    }
+
+    rates.retain(|_, x| x.time.elapsed().map_or(true, |x| x < RATE_RETENTION));
+    rates.insert(
+        String::from(target),
+        RateEntry {
+            time,
+            stats: stats.clone(),
+            rates: result.clone(),
+        },
+    );
+    result
+}
+
+/// Returns the changes of the links of the ports.
+fn link_changes(previous: Option<&SpeedStats>, current: Option<&SpeedStats>) -> Vec<LinkChange> {
+    let (previous, current) = match (previous, current) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return Vec::new(),
+    };
+    current
+        .stats
+        .iter()
+        .filter_map(|x| {
+            let y = previous.stats.iter().find(|y| y.port_no == x.port_no)?;
+            if x.link == y.link {
+                return None;
+            }
+            Some(LinkChange {
+                port_no: x.port_no,
+                link: x.link,
+                previous: y.link,
+            })
+        })
+        .collect()
+}
+
+/// Formats a message of Server-Sent Events.
+fn event<T: Serialize>(name: &str, value: &T) -> String {
+    let data = serde_json::to_string(value).unwrap_or_else(|x| format!("{}", x));
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+fn json<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    let body = serde_json::to_string(value).unwrap_or_else(|x| format!("{}", x));
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Scrape
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Settings of a scrape given by the headers of the request.
+#[derive(Clone, Copy, Debug, Default)]
+struct Scrape {
+    // Deadline of the queries given by the scrape timeout.
+    deadline: Option<Instant>,
+    openmetrics: bool,
+    gzip: bool,
+}
+
+impl Scrape {
+    fn new(req: &Request<Body>, context: &Context) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .unwrap_or("")
+        };
+
+        let accept = header("Accept");
+        let openmetrics = quality(accept, "application/openmetrics-text");
+        Scrape {
+            deadline: Scrape::deadline(header(SCRAPE_TIMEOUT_HEADER), context.timeout_offset),
+            openmetrics: openmetrics > 0.0 && openmetrics >= quality(accept, "text/plain"),
+            gzip: quality(header("Accept-Encoding"), "gzip") > 0.0,
+        }
+    }
 
-        let metric_familys = registry.gather();
+    /// Returns the deadline of the queries from the scrape timeout given by Prometheus.
+    ///
+    /// The offset is not subtracted if it exceeds the timeout.
+    fn deadline(timeout: &str, offset: Duration) -> Option<Instant> {
+        let timeout = timeout
+            .parse::<f64>()
+            .ok()
+            .filter(|x| *x > 0.0 && x.is_finite())?;
+        let timeout = Duration::from_secs_f64(timeout);
+        let timeout = match timeout.checked_sub(offset) {
+            Some(x) if x > Duration::from_secs(0) => x,
+            _ => timeout,
+        };
+        Some(Instant::now() + timeout)
+    }
+
+    /// Encodes the metrics in the negotiated format.
+    fn respond(
+        &self,
+        metric_familys: &[MetricFamily],
+        created: Created,
+        integers: Integers,
+    ) -> Response<Body> {
         let mut buffer = vec![];
-        let encoder = TextEncoder::new();
-        encoder.encode(&metric_familys, &mut buffer).unwrap();
-        Response::builder()
-            .header("Content-Type", "text/plain; version=0.0.4")
-            .body(Body::from(buffer))
-            .unwrap()
+        let content_type = if self.openmetrics {
+            let encoder = OpenMetricsEncoder::new()
+                .created(created)
+                .integers(integers);
+            encoder.encode(metric_familys, &mut buffer).unwrap();
+            String::from(encoder.format_type())
+        } else {
+            let encoder = TextEncoder::new().integers(integers);
+            encoder.encode(metric_familys, &mut buffer).unwrap();
+            String::from(encoder.format_type())
+        };
+
+        // The format and the compression are negotiated by the headers, which caches must take into account.
+        let mut response = Response::builder();
+        response.header("Content-Type", content_type.as_str());
+        response.header("Vary", "Accept, Accept-Encoding");
+        if self.gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            if let Ok(x) = encoder.write_all(&buffer).and_then(|_| encoder.finish()) {
+                buffer = x;
+                response.header("Content-Encoding", "gzip");
+            }
+        }
+        response.body(Body::from(buffer)).unwrap()
+    }
+}
+
+/// Returns the largest quality of `value` in `Accept` or `Accept-Encoding` header.
+fn quality(header: &str, value: &str) -> f64 {
+    header
+        .split(',')
+        .filter_map(|x| {
+            let mut params = x.split(';').map(str::trim);
+            if !params.next()?.eq_ignore_ascii_case(value) {
+                return None;
+            }
+            let q = params
+                .find_map(|x| x.strip_prefix("q="))
+                .map_or(Some(1.0), |x| x.parse::<f64>().ok())?;
+            Some(q)
+        })
+        .fold(0.0, f64::max)
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Telemetry
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Metrics of the exporter itself.
+#[derive(Clone)]
+struct Telemetry {
+    registry: Registry,
+    probes: IntCounterVec,
+    probe_duration: Histogram,
+    lock_wait: Histogram,
+    probes_in_flight: IntGauge,
+}
+
+impl Telemetry {
+    fn new() -> Self {
+        let registry = Exporter::registry();
+
+        let probes = IntCounterVec::new(PROBES_OPT.clone(), &["result"]).unwrap();
+        let probe_duration = Histogram::with_opts(PROBE_DURATION_OPT.clone()).unwrap();
+        let lock_wait = Histogram::with_opts(LOCK_WAIT_OPT.clone()).unwrap();
+        let probes_in_flight = IntGauge::with_opts(PROBES_IN_FLIGHT_OPT.clone()).unwrap();
+
+        let _ = registry.register(Box::new(probes.clone()));
+        let _ = registry.register(Box::new(probe_duration.clone()));
+        let _ = registry.register(Box::new(lock_wait.clone()));
+        let _ = registry.register(Box::new(probes_in_flight.clone()));
+        #[cfg(target_os = "linux")]
+        let _ = registry.register(Box::new(ProcessCollector::for_self()));
+
+        // Both results are exposed from the start so that the rate of failures can be alerted.
+        probes.with_label_values(&["success"]);
+        probes.with_label_values(&["failure"]);
+
+        Telemetry {
+            registry,
+            probes,
+            probe_duration,
+            lock_wait,
+            probes_in_flight,
+        }
+    }
+
+    fn render(&self, scrape: &Scrape) -> Response<Body> {
+        scrape.respond(&self.registry.gather(), Created::new(), Integers::new())
+    }
+}
+
+/// Counts a query in flight until it is finished or dropped.
+struct InFlight(IntGauge);
+
+impl InFlight {
+    fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        InFlight(gauge.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flight(finished: Option<Instant>) -> Flight {
+        let probe: SwitchFuture<ProbeResult> = Box::new(future::ok(ProbeResult::default()));
+        Flight {
+            probe: probe.shared(),
+            finished,
+        }
+    }
+
+    #[test]
+    fn test_sweep_flights() {
+        let ttl = Duration::from_millis(100);
+        let mut flights = HashMap::new();
+        flights.insert(String::from("in_flight"), flight(None));
+        flights.insert(String::from("fresh"), flight(Some(Instant::now())));
+        flights.insert(
+            String::from("expired"),
+            flight(Some(Instant::now() - Duration::from_secs(1))),
+        );
+
+        sweep_flights(&mut flights, ttl);
+        let mut keys: Vec<_> = flights.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["fresh", "in_flight"]);
+
+        std::thread::sleep(ttl);
+        sweep_flights(&mut flights, ttl);
+        assert_eq!(flights.keys().collect::<Vec<_>>(), vec!["in_flight"]);
+    }
+
+    #[test]
+    fn test_sweep_collector_cache() {
+        let cached = |age: Duration| CachedResult {
+            time: Instant::now() - age,
+            interval: Duration::from_secs(60),
+            result: ProbeResult::default(),
+        };
+        let mut cache = HashMap::new();
+        cache.insert(
+            (String::from("sw1"), Collector::Info),
+            cached(Duration::from_secs(0)),
+        );
+        cache.insert(
+            (String::from("sw2"), Collector::Info),
+            cached(Duration::from_secs(120)),
+        );
+
+        sweep_collector_cache(&mut cache);
+        let keys: Vec<_> = cache.keys().cloned().collect();
+        assert_eq!(keys, vec![(String::from("sw1"), Collector::Info)]);
+    }
+
+    #[test]
+    fn test_port_rates_retention() {
+        let rates: RateCache = Arc::new(Mutex::new(HashMap::new()));
+        let stats = PortStats { stats: Vec::new() };
+        let now = SystemTime::now();
+        port_rates(&rates, "old", now - RATE_RETENTION * 2, &stats);
+        port_rates(&rates, "recent", now - RATE_RETENTION / 2, &stats);
+        port_rates(&rates, "new", now, &stats);
+
+        let mut keys: Vec<_> = rates.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["new", "recent"]);
     }
 }
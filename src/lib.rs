@@ -0,0 +1,48 @@
+//! Client of NETGEAR switches supported by ProSAFE Plus utility, and Prometheus exporter built on it.
+//!
+//! The client speaks NSDP (NETGEAR Switch Discovery Protocol) over UDP.
+//! The exporter is enabled by `exporter` feature, and the switch simulator is enabled by `simulator` feature.
+//! Both are on by default.
+//! Recording and replaying exchanges with switches is enabled by `dump` feature, which `exporter` feature includes.
+//! The Prometheus collector of switches for embedding into other programs is enabled by `collector` feature, which
+//! `exporter` feature includes too.
+//! Library users who need only the client can disable them to avoid HTTP dependencies:
+//!
+//! ```toml
+//! prosafe_exporter = { version = "0.2", default-features = false }
+//! ```
+//!
+//! # Example
+//!
+//! ```no_run
+//! use prosafe_exporter::ProSafeSwitch;
+//!
+//! let sw = ProSafeSwitch::new("switch1", "eth0");
+//! for s in sw.port_stat().unwrap().stats {
+//!     println!("port{}: rx {} bytes, tx {} bytes", s.port_no, s.recv_bytes, s.send_bytes);
+//! }
+//! ```
+
+#[cfg(feature = "collector")]
+pub mod collector;
+#[cfg(feature = "exporter")]
+pub mod config;
+#[cfg(feature = "dump")]
+pub mod dump;
+#[cfg(feature = "exporter")]
+pub mod exporter;
+#[cfg(feature = "exporter")]
+pub mod openmetrics;
+pub mod prosafe_switch;
+#[cfg(feature = "simulator")]
+pub mod simulator;
+pub mod transport;
+
+pub use crate::prosafe_switch::{
+    Capabilities, Capability, Duplex, Link, PortCount, PortStat, PortStats, ProSafeSwitch,
+    SpeedStat, SpeedStats, SwitchFuture, SwitchInfo,
+};
+pub use crate::transport::{
+    udp_connector, udp_connector_with, Connector, MockTransport, ReplayTransport, Transport,
+    UdpMux, UdpOptions, UdpTransport,
+};
@@ -1,8 +1,9 @@
-mod exporter;
-mod prosafe_switch;
-
-use crate::exporter::Exporter;
 use failure::Error;
+use prosafe_exporter::config::Config;
+use prosafe_exporter::dump::replay_connector;
+use prosafe_exporter::exporter::Exporter;
+use std::path::PathBuf;
+use std::time::Duration;
 use structopt::{clap, StructOpt};
 
 // -------------------------------------------------------------------------------------------------
@@ -19,9 +20,41 @@ pub struct Opt {
     #[structopt(long = "web.listen-address", default_value = ":9493")]
     pub listen_address: String,
 
-    /// Static target probed through /metrics.
-    #[structopt(long = "target")]
-    pub target: Option<String>,
+    /// Static target probed through /metrics: `host:if_name` or the name of a configured target.
+    /// It can be repeated. All configured targets are probed if it is omitted.
+    #[structopt(long = "target", number_of_values = 1)]
+    pub target: Vec<String>,
+
+    /// TOML file describing named targets.
+    #[structopt(long = "config.file", parse(from_os_str))]
+    pub config_file: Option<PathBuf>,
+
+    /// Interval in milliseconds of polling the static targets in the background.
+    /// Scrapes are served from the last result.
+    #[structopt(long = "poll.interval")]
+    pub poll_interval: Option<u64>,
+
+    /// Age in milliseconds of the polled result after which its metrics are dropped.
+    #[structopt(long = "poll.staleness", default_value = "60000")]
+    pub poll_staleness: u64,
+
+    /// Time in milliseconds for which the result of a probe is reused by the following probes of the
+    /// same target. Concurrent probes always share one query.
+    #[structopt(long = "probe.ttl", default_value = "0")]
+    pub probe_ttl: u64,
+
+    /// Time in milliseconds subtracted from the scrape timeout given by Prometheus. The queries of a
+    /// scrape stop at the rest of the timeout, and the collected metrics are returned.
+    #[structopt(long = "probe.timeout-offset", default_value = "500")]
+    pub probe_timeout_offset: u64,
+
+    /// Directory to which every request/reply pair with switches is written.
+    #[structopt(long = "dump-dir", parse(from_os_str))]
+    pub dump_dir: Option<PathBuf>,
+
+    /// Directory written by --dump-dir, from which replies are served instead of the network.
+    #[structopt(long = "replay", parse(from_os_str))]
+    pub replay: Option<PathBuf>,
 
     /// Show verbose message
     #[structopt(short = "v", long = "verbose")]
@@ -34,8 +67,22 @@ pub struct Opt {
 
 fn run() -> Result<(), Error> {
     let opt = Opt::from_args();
-    let _ = Exporter::start(&opt.listen_address, opt.target, opt.verbose);
-    Ok(())
+
+    let mut exporter = Exporter::new(&opt.listen_address)
+        .targets(opt.target)
+        .dump_dir(opt.dump_dir)
+        .poll_interval(opt.poll_interval.map(Duration::from_millis))
+        .staleness(Duration::from_millis(opt.poll_staleness))
+        .probe_ttl(Duration::from_millis(opt.probe_ttl))
+        .timeout_offset(Duration::from_millis(opt.probe_timeout_offset))
+        .verbose(opt.verbose);
+    if let Some(ref path) = opt.config_file {
+        exporter = exporter.config(Config::load(path)?);
+    }
+    if let Some(ref dir) = opt.replay {
+        exporter = exporter.connector(replay_connector(dir)?);
+    }
+    exporter.start()
 }
 
 fn main() {
@@ -0,0 +1,535 @@
+use crate::collector::{series_key, Integers};
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use prometheus::{Encoder, Result, TEXT_FORMAT};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Content type of OpenMetrics text format.
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Units found from the suffix of metric names.
+const UNITS: &[&str] = &["seconds", "bytes"];
+
+// ---------------------------------------------------------------------------------------------------------------------
+// CounterResets
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Creation times of counters in seconds since UNIX epoch by the series.
+pub type Created = HashMap<String, f64>;
+
+/// Age after which a series not updated is dropped, so that the series of ad-hoc targets don't stay forever.
+const SERIES_RETENTION: Duration = Duration::from_secs(600);
+
+/// Tracker of the counters of switches, which finds the time at which each counter was reset.
+///
+/// The counters of a switch start at its boot, which is unknown. So a counter has the creation time only after a
+/// reset is found by its decrease.
+#[derive(Debug, Default)]
+pub struct CounterResets {
+    series: Mutex<HashMap<String, Series>>,
+}
+
+#[derive(Debug)]
+struct Series {
+    value: f64,
+    reset: Option<f64>,
+    // Time of the last update in seconds since UNIX epoch.
+    seen: f64,
+}
+
+impl CounterResets {
+    pub fn new() -> Self {
+        CounterResets::default()
+    }
+
+    /// Records the counters in `families`, and returns the creation times of them.
+    ///
+    /// `scope` tells apart the same series from different switches, like the target of `/probe`.
+    pub fn update(&self, scope: &str, families: &[MetricFamily]) -> Created {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        self.update_at(scope, families, now)
+    }
+
+    fn update_at(&self, scope: &str, families: &[MetricFamily], now: f64) -> Created {
+        let mut series = self.series.lock().unwrap();
+        series.retain(|_, x| now - x.seen < SERIES_RETENTION.as_secs_f64());
+        let mut created = Created::new();
+        for family in families {
+            if family.get_field_type() != MetricType::COUNTER {
+                continue;
+            }
+            for metric in family.get_metric() {
+                let key = series_key(family.get_name(), metric);
+                let value = metric.get_counter().get_value();
+                let entry = series
+                    .entry(format!("{}/{}", scope, key))
+                    .or_insert(Series {
+                        value,
+                        reset: None,
+                        seen: now,
+                    });
+                if value < entry.value {
+                    entry.reset = Some(now);
+                }
+                entry.value = value;
+                entry.seen = now;
+                if let Some(reset) = entry.reset {
+                    created.insert(key, reset);
+                }
+            }
+        }
+        created
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// OpenMetricsEncoder
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Encoder of OpenMetrics text format.
+///
+/// `_total` suffix of counters is moved from the family to the samples, and the unit is given by `# UNIT` if the
+/// name ends with it.
+#[derive(Debug, Default)]
+pub struct OpenMetricsEncoder {
+    created: Created,
+    integers: Integers,
+}
+
+impl OpenMetricsEncoder {
+    pub fn new() -> Self {
+        OpenMetricsEncoder::default()
+    }
+
+    /// Sets the creation times of counters returned by [`CounterResets::update`].
+    pub fn created(mut self, created: Created) -> Self {
+        self.created = created;
+        self
+    }
+
+    /// Sets the exact values of integer counters returned by [`families_with_integers`].
+    ///
+    /// [`families_with_integers`]: crate::collector::families_with_integers
+    pub fn integers(mut self, integers: Integers) -> Self {
+        self.integers = integers;
+        self
+    }
+
+    fn encode_family<W: Write>(&self, family: &MetricFamily, writer: &mut W) -> Result<()> {
+        let name = family.get_name();
+        let (base, kind) = match family.get_field_type() {
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+
+        writeln!(writer, "# TYPE {} {}", base, kind)?;
+        if let Some(unit) = UNITS.iter().find(|x| base.ends_with(&format!("_{}", x))) {
+            writeln!(writer, "# UNIT {} {}", base, unit)?;
+        }
+        if !family.get_help().is_empty() {
+            writeln!(writer, "# HELP {} {}", base, escape(family.get_help()))?;
+        }
+
+        for metric in family.get_metric() {
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let key = series_key(name, metric);
+                    let value = counter_value(&self.integers, &key, metric);
+                    write_sample(writer, base, "_total", metric, None, &value)?;
+                    if let Some(created) = self.created.get(&key) {
+                        write_sample(writer, base, "_created", metric, None, &number(*created))?;
+                    }
+                }
+                MetricType::GAUGE => {
+                    let value = number(metric.get_gauge().get_value());
+                    write_sample(writer, base, "", metric, None, &value)?;
+                }
+                MetricType::UNTYPED => {
+                    let value = number(metric.get_untyped().get_value());
+                    write_sample(writer, base, "", metric, None, &value)?;
+                }
+                MetricType::HISTOGRAM => {
+                    let h = metric.get_histogram();
+                    let mut inf = false;
+                    for bucket in h.get_bucket() {
+                        let le = bucket.get_upper_bound();
+                        inf |= le == f64::INFINITY;
+                        let count = bucket.get_cumulative_count().to_string();
+                        write_sample(writer, base, "_bucket", metric, Some(("le", le)), &count)?;
+                    }
+                    let count = h.get_sample_count().to_string();
+                    if !inf {
+                        let le = f64::INFINITY;
+                        write_sample(writer, base, "_bucket", metric, Some(("le", le)), &count)?;
+                    }
+                    let sum = number(h.get_sample_sum());
+                    write_sample(writer, base, "_count", metric, None, &count)?;
+                    write_sample(writer, base, "_sum", metric, None, &sum)?;
+                }
+                MetricType::SUMMARY => {
+                    let s = metric.get_summary();
+                    for quantile in s.get_quantile() {
+                        let q = Some(("quantile", quantile.get_quantile()));
+                        let value = number(quantile.get_value());
+                        write_sample(writer, base, "", metric, q, &value)?;
+                    }
+                    let count = s.get_sample_count().to_string();
+                    let sum = number(s.get_sample_sum());
+                    write_sample(writer, base, "_count", metric, None, &count)?;
+                    write_sample(writer, base, "_sum", metric, None, &sum)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(&self, families: &[MetricFamily], writer: &mut W) -> Result<()> {
+        for family in families {
+            self.encode_family(family, writer)?;
+        }
+        writeln!(writer, "# EOF")?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+fn write_sample<W: Write>(
+    writer: &mut W,
+    name: &str,
+    suffix: &str,
+    metric: &Metric,
+    extra: Option<(&str, f64)>,
+    value: &str,
+) -> Result<()> {
+    write!(writer, "{}{}", name, suffix)?;
+
+    let mut labels: Vec<_> = metric
+        .get_label()
+        .iter()
+        .map(|x| format!("{}=\"{}\"", x.get_name(), escape(x.get_value())))
+        .collect();
+    if let Some((name, value)) = extra {
+        labels.push(format!("{}=\"{}\"", name, canonical(value)));
+    }
+    if !labels.is_empty() {
+        write!(writer, "{{{}}}", labels.join(","))?;
+    }
+
+    write!(writer, " {}", value)?;
+    if metric.has_timestamp_ms() {
+        write!(writer, " {}", metric.get_timestamp_ms() as f64 / 1000.0)?;
+    }
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// Returns the value of a counter, which is exact if it is an integer counter.
+fn counter_value(integers: &Integers, key: &str, metric: &Metric) -> String {
+    match integers.get(key) {
+        Some(x) => x.to_string(),
+        None => number(metric.get_counter().get_value()),
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// TextEncoder
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Encoder of Prometheus text format like [`prometheus::TextEncoder`], which writes integer counters exactly.
+#[derive(Debug, Default)]
+pub struct TextEncoder {
+    integers: Integers,
+}
+
+impl TextEncoder {
+    pub fn new() -> Self {
+        TextEncoder::default()
+    }
+
+    /// Sets the exact values of integer counters returned by [`families_with_integers`].
+    ///
+    /// [`families_with_integers`]: crate::collector::families_with_integers
+    pub fn integers(mut self, integers: Integers) -> Self {
+        self.integers = integers;
+        self
+    }
+
+    fn encode_family<W: Write>(&self, family: &MetricFamily, writer: &mut W) -> Result<()> {
+        let name = family.get_name();
+        if !family.get_help().is_empty() {
+            let help = family.get_help().replace('\\', r"\\").replace('\n', r"\n");
+            writeln!(writer, "# HELP {} {}", name, help)?;
+        }
+        let kind = format!("{:?}", family.get_field_type()).to_lowercase();
+        writeln!(writer, "# TYPE {} {}", name, kind)?;
+
+        for metric in family.get_metric() {
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let value = counter_value(&self.integers, &series_key(name, metric), metric);
+                    write_text_sample(writer, name, metric, None, &value)?;
+                }
+                MetricType::GAUGE => {
+                    let value = number(metric.get_gauge().get_value());
+                    write_text_sample(writer, name, metric, None, &value)?;
+                }
+                MetricType::UNTYPED => {
+                    let value = number(metric.get_untyped().get_value());
+                    write_text_sample(writer, name, metric, None, &value)?;
+                }
+                MetricType::HISTOGRAM => {
+                    let h = metric.get_histogram();
+                    let bucket = format!("{}_bucket", name);
+                    let mut inf = false;
+                    for x in h.get_bucket() {
+                        inf |= x.get_upper_bound() == f64::INFINITY;
+                        let le = Some(("le", number(x.get_upper_bound())));
+                        let count = x.get_cumulative_count().to_string();
+                        write_text_sample(writer, &bucket, metric, le, &count)?;
+                    }
+                    let count = h.get_sample_count().to_string();
+                    if !inf {
+                        let le = Some(("le", number(f64::INFINITY)));
+                        write_text_sample(writer, &bucket, metric, le, &count)?;
+                    }
+                    let sum = number(h.get_sample_sum());
+                    write_text_sample(writer, &format!("{}_sum", name), metric, None, &sum)?;
+                    write_text_sample(writer, &format!("{}_count", name), metric, None, &count)?;
+                }
+                MetricType::SUMMARY => {
+                    let s = metric.get_summary();
+                    for x in s.get_quantile() {
+                        let q = Some(("quantile", number(x.get_quantile())));
+                        write_text_sample(writer, name, metric, q, &number(x.get_value()))?;
+                    }
+                    let count = s.get_sample_count().to_string();
+                    let sum = number(s.get_sample_sum());
+                    write_text_sample(writer, &format!("{}_sum", name), metric, None, &sum)?;
+                    write_text_sample(writer, &format!("{}_count", name), metric, None, &count)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Encoder for TextEncoder {
+    fn encode<W: Write>(&self, families: &[MetricFamily], writer: &mut W) -> Result<()> {
+        for family in families {
+            self.encode_family(family, writer)?;
+        }
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        TEXT_FORMAT
+    }
+}
+
+fn write_text_sample<W: Write>(
+    writer: &mut W,
+    name: &str,
+    metric: &Metric,
+    extra: Option<(&str, String)>,
+    value: &str,
+) -> Result<()> {
+    write!(writer, "{}", name)?;
+
+    let mut labels: Vec<_> = metric
+        .get_label()
+        .iter()
+        .map(|x| format!("{}=\"{}\"", x.get_name(), escape(x.get_value())))
+        .collect();
+    if let Some((name, value)) = extra {
+        labels.push(format!("{}=\"{}\"", name, value));
+    }
+    if !labels.is_empty() {
+        write!(writer, "{{{}}}", labels.join(","))?;
+    }
+
+    write!(writer, " {}", value)?;
+    if metric.get_timestamp_ms() != 0 {
+        write!(writer, " {}", metric.get_timestamp_ms())?;
+    }
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// Escapes `\`, new line and `"`.
+fn escape(x: &str) -> String {
+    x.replace('\\', r"\\")
+        .replace('\n', r"\n")
+        .replace('"', r#"\""#)
+}
+
+fn number(x: f64) -> String {
+    if x.is_nan() {
+        String::from("NaN")
+    } else if x == f64::INFINITY {
+        String::from("+Inf")
+    } else if x == f64::NEG_INFINITY {
+        String::from("-Inf")
+    } else {
+        format!("{}", x)
+    }
+}
+
+/// Formats `le` and `quantile` label values, which are always floating point numbers like `1.0`.
+fn canonical(x: f64) -> String {
+    if x.is_finite() && x.fract() == 0.0 {
+        format!("{:.1}", x)
+    } else {
+        number(x)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::core::Collector;
+    use prometheus::{CounterVec, Gauge, Histogram, HistogramOpts, Opts};
+
+    fn encode<E: Encoder>(encoder: &E, families: &[MetricFamily]) -> String {
+        let mut buffer = vec![];
+        encoder.encode(families, &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    fn families() -> Vec<MetricFamily> {
+        let counter = CounterVec::new(
+            Opts::new("test_receive_bytes_total", "Incoming \"transfer\"."),
+            &["port"],
+        )
+        .unwrap();
+        counter.with_label_values(&["1\n"]).inc_by(1000.0);
+        let gauge = Gauge::new("test_link_speed", "Link speed.").unwrap();
+        gauge.set(f64::NAN);
+        let histogram = Histogram::with_opts(
+            HistogramOpts::new("test_duration_seconds", "Duration.").buckets(vec![0.5, 1.0]),
+        )
+        .unwrap();
+        histogram.observe(0.7);
+
+        let mut families = counter.collect();
+        families.extend(gauge.collect());
+        families.extend(histogram.collect());
+        families
+    }
+
+    #[test]
+    fn test_encode() {
+        let expected = r#"# TYPE test_receive_bytes counter
+# UNIT test_receive_bytes bytes
+# HELP test_receive_bytes Incoming \"transfer\".
+test_receive_bytes_total{port="1\n"} 1000
+# TYPE test_link_speed gauge
+# HELP test_link_speed Link speed.
+test_link_speed NaN
+# TYPE test_duration_seconds histogram
+# UNIT test_duration_seconds seconds
+# HELP test_duration_seconds Duration.
+test_duration_seconds_bucket{le="0.5"} 0
+test_duration_seconds_bucket{le="1.0"} 1
+test_duration_seconds_bucket{le="+Inf"} 1
+test_duration_seconds_count 1
+test_duration_seconds_sum 0.7
+# EOF
+"#;
+        assert_eq!(encode(&OpenMetricsEncoder::new(), &families()), expected);
+    }
+
+    #[test]
+    fn test_text_encode() {
+        assert_eq!(
+            encode(&TextEncoder::new(), &families()),
+            encode(&prometheus::TextEncoder::new(), &families())
+        );
+    }
+
+    #[test]
+    fn test_integers() {
+        // 2^53 + 1, which is rounded by `f64` of the counter.
+        let value = 9007199254740993u64;
+        let counter = CounterVec::new(Opts::new("test_total", "Test."), &["port"]).unwrap();
+        counter.with_label_values(&["1"]).inc_by(value as f64);
+        let families = counter.collect();
+        let mut integers = Integers::new();
+        integers.insert(
+            series_key("test_total", &families[0].get_metric()[0]),
+            value,
+        );
+
+        let text = encode(&TextEncoder::new().integers(integers.clone()), &families);
+        assert!(text.contains("test_total{port=\"1\"} 9007199254740993\n"));
+        let text = encode(&OpenMetricsEncoder::new().integers(integers), &families);
+        assert!(text.contains("test_total{port=\"1\"} 9007199254740993\n"));
+        let text = encode(&TextEncoder::new(), &families);
+        assert!(text.contains("test_total{port=\"1\"} 9007199254740992\n"));
+    }
+
+    #[test]
+    fn test_created() {
+        let resets = CounterResets::new();
+        let counter = |value: f64| {
+            let counter = CounterVec::new(Opts::new("test_total", "Test."), &["port"]).unwrap();
+            counter.with_label_values(&["1"]).inc_by(value);
+            counter.collect()
+        };
+
+        assert!(resets.update("sw1", &counter(10.0)).is_empty());
+        assert!(resets.update("sw1", &counter(20.0)).is_empty());
+        // Another switch doesn't reset the counter.
+        assert!(resets.update("sw2", &counter(5.0)).is_empty());
+
+        let created = resets.update("sw1", &counter(3.0));
+        let time = created["test_total,port=\"1\""];
+        let encoder = OpenMetricsEncoder::new().created(created);
+        let text = encode(&encoder, &counter(3.0));
+        assert!(text.contains(&format!("test_created{{port=\"1\"}} {}\n", time)));
+
+        // The time is kept until the next reset.
+        assert_eq!(
+            resets.update("sw1", &counter(4.0))["test_total,port=\"1\""],
+            time
+        );
+    }
+
+    #[test]
+    fn test_created_retention() {
+        let resets = CounterResets::new();
+        let counter = |value: f64| {
+            let counter = CounterVec::new(Opts::new("test_total", "Test."), &["port"]).unwrap();
+            counter.with_label_values(&["1"]).inc_by(value);
+            counter.collect()
+        };
+        let retention = SERIES_RETENTION.as_secs_f64();
+
+        resets.update_at("old", &counter(10.0), 0.0);
+        resets.update_at("recent", &counter(10.0), retention);
+        resets.update_at("new", &counter(10.0), retention * 1.5);
+
+        let mut keys: Vec<_> = resets.series.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec!["new/test_total,port=\"1\"", "recent/test_total,port=\"1\""]
+        );
+    }
+}
@@ -1,27 +1,59 @@
+use crate::transport::{udp_connector, Connector, Transport};
 use bincode::Options;
 use combine::byte::bytes;
 use combine::byte::num::{be_u16, be_u64};
-use combine::{any, count, skip_count};
+use combine::{any, count_min_max, skip_count_min_max};
 use combine::{ParseError, Parser, Stream};
 use failure::format_err;
 use failure::Error;
-use interfaces::{HardwareAddr, Interface};
-use rand;
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream as _};
+use interfaces::Interface;
 use serde::{Deserialize, Serialize};
-use std::net::{ToSocketAddrs, UdpSocket};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::current_thread::Runtime;
 
 // ---------------------------------------------------------------------------------------------------------------------
 // QueryRequest
 // ---------------------------------------------------------------------------------------------------------------------
 
 #[repr(u32)]
-enum Cmd {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Cmd {
+    Model = 0x0001_0000,
+    Name = 0x0003_0000,
+    Mac = 0x0004_0000,
+    FirmwareVersion = 0x000d_0000,
     PortStat = 0x1000_0000,
     SpeedStat = 0x0c00_0000,
+    PortCount = 0x6000_0000,
     End = 0xffff_0000,
 }
 
+impl Cmd {
+    pub(crate) fn tag(self) -> u16 {
+        (self as u32 >> 16) as u16
+    }
+
+    #[cfg(feature = "dump")]
+    pub(crate) fn from_tag(tag: u16) -> Option<Cmd> {
+        [
+            Cmd::Model,
+            Cmd::Name,
+            Cmd::Mac,
+            Cmd::FirmwareVersion,
+            Cmd::PortStat,
+            Cmd::SpeedStat,
+            Cmd::PortCount,
+            Cmd::End,
+        ]
+        .iter()
+        .copied()
+        .find(|x| x.tag() == tag)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct QueryRequest {
     ctype: u16,
@@ -31,30 +63,33 @@ struct QueryRequest {
     padding2: [u8; 2],
     seq: u16,
     fix: [u8; 8],
-    cmd: [u32; 2],
+    #[serde(skip)]
+    cmd: Vec<u32>,
 }
 
 impl QueryRequest {
-    fn new(cmd: Cmd, src_mac: HardwareAddr, dst_mac: HardwareAddr) -> Self {
-        let mut src: [u8; 6] = Default::default();
-        let mut dst: [u8; 6] = Default::default();
-        src.copy_from_slice(src_mac.as_bytes());
-        dst.copy_from_slice(dst_mac.as_bytes());
+    fn new(cmd: &[Cmd], src_mac: [u8; 6], dst_mac: [u8; 6]) -> Self {
+        let mut cmd: Vec<u32> = cmd.iter().map(|x| *x as u32).collect();
+        cmd.push(Cmd::End as u32);
         QueryRequest {
             ctype: 0x0101u16,
             padding1: [0; 6],
-            src_mac: src,
-            dst_mac: dst,
+            src_mac,
+            dst_mac,
             padding2: [0; 2],
             seq: rand::random(),
             fix: [b'N', b'S', b'D', b'P', 0, 0, 0, 0],
-            cmd: [cmd as u32, Cmd::End as u32],
+            cmd,
         }
     }
 
     fn encode(&self) -> Result<Vec<u8>, Error> {
         let config = bincode::options().with_big_endian().with_fixint_encoding();
-        Ok(config.serialize(&self)?)
+        let mut ret = config.serialize(&self)?;
+        for cmd in &self.cmd {
+            ret.extend_from_slice(&cmd.to_be_bytes());
+        }
+        Ok(ret)
     }
 }
 
@@ -73,10 +108,21 @@ impl ResponseParser {
         bytes(&[0x01, 0x02])
             .and(be_u16())
             .and(be_u16())
-            .and(skip_count(26, any()))
+            .and(skip_count_min_max(26, 26, any()))
             .map(|(((_a, b), c), _d)| (b, c))
     }
 
+    #[cfg(any(feature = "simulator", test))]
+    fn request_header<'a, I>() -> impl Parser<Input = I, Output = ()>
+    where
+        I: Stream<Item = u8, Range = &'a [u8]>,
+        I::Error: ParseError<I::Item, I::Range, I::Position>,
+    {
+        bytes(&[0x01, 0x01])
+            .and(skip_count_min_max(30, 30, any()))
+            .map(|_| ())
+    }
+
     fn payload_header<'a, I>() -> impl Parser<Input = I, Output = (u16, u16)>
     where
         I: Stream<Item = u8, Range = &'a [u8]>,
@@ -90,7 +136,7 @@ impl ResponseParser {
         I: Stream<Item = u8, Range = &'a [u8]>,
         I::Error: ParseError<I::Item, I::Range, I::Position>,
     {
-        count::<Vec<_>, _>(len as usize, any())
+        count_min_max::<Vec<_>, _>(len as usize, len as usize, any())
     }
 
     fn port_stats<'a, I>() -> impl Parser<Input = I, Output = (u8, Vec<u64>)>
@@ -98,7 +144,7 @@ impl ResponseParser {
         I: Stream<Item = u8, Range = &'a [u8]>,
         I::Error: ParseError<I::Item, I::Range, I::Position>,
     {
-        any().and(count::<Vec<_>, _>(6, be_u64()))
+        any().and(count_min_max::<Vec<_>, _>(6, 6, be_u64()))
     }
 
     fn speed_stats<'a, I>() -> impl Parser<Input = I, Output = (u8, Vec<u8>)>
@@ -106,7 +152,7 @@ impl ResponseParser {
         I: Stream<Item = u8, Range = &'a [u8]>,
         I::Error: ParseError<I::Item, I::Range, I::Position>,
     {
-        any().and(count::<Vec<_>, _>(2, any()))
+        any().and(count_min_max::<Vec<_>, _>(2, 2, any()))
     }
 }
 
@@ -114,10 +160,10 @@ impl ResponseParser {
 // QueryResponse
 // ---------------------------------------------------------------------------------------------------------------------
 
-struct QueryResponse;
+pub(crate) struct QueryResponse;
 
 impl QueryResponse {
-    fn decode(dat: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    pub(crate) fn decode(dat: &[u8]) -> Result<Vec<(u16, Vec<u8>)>, Error> {
         let (_, rest) = ResponseParser::header()
             .parse(dat)
             .map_err(|x| format_err!("failed to parse: {:?}", x))?;
@@ -138,37 +184,93 @@ impl QueryResponse {
                 break;
             }
 
-            ret.push(dat);
+            ret.push((cmd, dat));
         }
 
         Ok(ret)
     }
+
+    /// Decodes the queried tags of a request.
+    #[cfg(any(feature = "simulator", test))]
+    pub(crate) fn decode_request(dat: &[u8]) -> Result<Vec<u16>, Error> {
+        let (_, rest) = ResponseParser::request_header()
+            .parse(dat)
+            .map_err(|x| format_err!("failed to parse: {:?}", x))?;
+        let mut ret = Vec::new();
+        let mut buf = rest;
+        while !buf.is_empty() {
+            let ((cmd, len), rest) = ResponseParser::payload_header()
+                .parse(buf)
+                .map_err(|x| format_err!("failed to parse: {:?}", x))?;
+            buf = rest;
+
+            let (_, rest) = ResponseParser::payload_body(len)
+                .parse(buf)
+                .map_err(|x| format_err!("failed to parse: {:?}", x))?;
+            buf = rest;
+
+            if cmd == 0xffff {
+                break;
+            }
+
+            ret.push(cmd);
+        }
+
+        Ok(ret)
+    }
+
+    /// Encodes the reply to `request` from pairs of tag and value.
+    #[cfg(any(feature = "simulator", test))]
+    pub(crate) fn encode(request: &[u8], dev_mac: [u8; 6], records: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut ret = vec![0; 32];
+        let len = request.len().min(32);
+        ret[..len].copy_from_slice(&request[..len]);
+        ret[0] = 0x01;
+        ret[1] = 0x02;
+        ret[2] = 0;
+        ret[3] = 0;
+        ret[14..20].copy_from_slice(&dev_mac);
+        for (cmd, dat) in records {
+            ret.extend_from_slice(&cmd.to_be_bytes());
+            ret.extend_from_slice(&(dat.len() as u16).to_be_bytes());
+            ret.extend_from_slice(dat);
+        }
+        ret.extend_from_slice(&(Cmd::End as u32).to_be_bytes());
+        ret
+    }
 }
 
 // ---------------------------------------------------------------------------------------------------------------------
 // PortStats
 // ---------------------------------------------------------------------------------------------------------------------
 
-#[derive(Debug, PartialEq)]
+/// Traffic counters of all ports.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PortStats {
     pub stats: Vec<PortStat>,
 }
 
-#[derive(Debug, PartialEq)]
+/// Traffic counters of a port.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PortStat {
+    /// Port number starting from 1.
     pub port_no: u8,
+    /// Received bytes.
     pub recv_bytes: u64,
+    /// Sent bytes.
     pub send_bytes: u64,
+    /// CRC error packets.
     pub error_pkts: u64,
 }
 
 impl PortStats {
-    fn decode(dat: &[u8]) -> Result<Self, Error> {
+    /// Decodes a reply of the query.
+    pub fn decode(dat: &[u8]) -> Result<Self, Error> {
         let dat = QueryResponse::decode(dat)?;
         let mut stats = Vec::new();
-        for d in dat {
+        for (_, d) in dat.iter().filter(|(x, _)| *x == Cmd::PortStat.tag()) {
             let ((port_no, metrics), _rest) = ResponseParser::port_stats()
-                .parse(&d as &[u8])
+                .parse(d as &[u8])
                 .map_err(|x| format_err!("failed to parse: {:?}", x))?;
 
             let stat = PortStat {
@@ -182,134 +284,628 @@ impl PortStats {
 
         Ok(PortStats { stats })
     }
+
+    /// Checks that every port number is unique and within `1..=port_count`.
+    pub fn validate(&self, port_count: &PortCount) -> Result<(), Error> {
+        validate_port_no(self.stats.iter().map(|x| x.port_no), port_count.count)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// PortCount
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Number of ports of a switch.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PortCount {
+    pub count: u8,
+}
+
+impl PortCount {
+    /// Decodes a reply of the query.
+    pub fn decode(dat: &[u8]) -> Result<Self, Error> {
+        let dat = QueryResponse::decode(dat)?;
+        let (_, d) = dat
+            .iter()
+            .find(|(x, _)| *x == Cmd::PortCount.tag())
+            .ok_or_else(|| format_err!("failed to parse: number of ports is missing"))?;
+        let (count, _rest) = any()
+            .parse(d as &[u8])
+            .map_err(|x| format_err!("failed to parse: {:?}", x))?;
+
+        Ok(PortCount { count })
+    }
+}
+
+fn validate_port_no<I: Iterator<Item = u8>>(ports: I, count: u8) -> Result<(), Error> {
+    let mut found = [false; 256];
+    for port_no in ports {
+        if port_no == 0 || port_no > count {
+            return Err(format_err!(
+                "invalid port number: {} is out of range 1-{}",
+                port_no,
+                count
+            ));
+        }
+        if found[port_no as usize] {
+            return Err(format_err!(
+                "invalid port number: {} is duplicated",
+                port_no
+            ));
+        }
+        found[port_no as usize] = true;
+    }
+    Ok(())
 }
 
 // ---------------------------------------------------------------------------------------------------------------------
 // SpeedStats
 // ---------------------------------------------------------------------------------------------------------------------
 
-#[derive(Debug, PartialEq)]
+/// Link status of all ports.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct SpeedStats {
     pub stats: Vec<SpeedStat>,
 }
 
-#[derive(Debug, PartialEq)]
+/// Link status of a port.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct SpeedStat {
+    /// Port number starting from 1.
     pub port_no: u8,
     pub link: Link,
+    pub duplex: Duplex,
 }
 
-#[derive(Debug, PartialEq)]
+/// Negotiated link speed. It is serialized like the link of the simulator ( e.g. `1G` ).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum Link {
+    #[serde(rename = "down")]
     None,
+    #[serde(rename = "10M")]
     Speed10Mbps,
+    #[serde(rename = "100M")]
     Speed100Mbps,
+    #[serde(rename = "1G")]
     Speed1Gbps,
+    #[serde(rename = "10G")]
     Speed10Gbps,
+    #[serde(rename = "unknown")]
     Unknown,
 }
 
+/// Duplex mode of a link. It is serialized in lower case ( e.g. `full` ).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum Duplex {
+    #[serde(rename = "down")]
+    None,
+    #[serde(rename = "half")]
+    Half,
+    #[serde(rename = "full")]
+    Full,
+    #[serde(rename = "unknown")]
+    Unknown,
+}
+
+impl Link {
+    /// Returns the speed in Mbps, which is 0 if the link is down or unknown.
+    pub fn mbps(self) -> u32 {
+        match self {
+            Link::None => 0,
+            Link::Speed10Mbps => 10,
+            Link::Speed100Mbps => 100,
+            Link::Speed1Gbps => 1000,
+            Link::Speed10Gbps => 10000,
+            Link::Unknown => 0,
+        }
+    }
+}
+
 impl SpeedStats {
-    fn decode(dat: &[u8]) -> Result<Self, Error> {
+    /// Decodes a reply of the query.
+    pub fn decode(dat: &[u8]) -> Result<Self, Error> {
         let dat = QueryResponse::decode(dat)?;
         let mut stats = Vec::new();
-        for d in dat {
+        for (_, d) in dat.iter().filter(|(x, _)| *x == Cmd::SpeedStat.tag()) {
             let ((port_no, metrics), _rest) = ResponseParser::speed_stats()
-                .parse(&d as &[u8])
+                .parse(d as &[u8])
                 .map_err(|x| format_err!("failed to parse: {:?}", x))?;
 
-            let link = match metrics[0] {
-                0 => Link::None,
-                1 => Link::Speed10Mbps,
-                2 => Link::Speed10Mbps,
-                3 => Link::Speed100Mbps,
-                4 => Link::Speed100Mbps,
-                5 => Link::Speed1Gbps,
-                6 => Link::Speed10Gbps,
-                _ => Link::Unknown,
+            let (link, duplex) = match metrics[0] {
+                0 => (Link::None, Duplex::None),
+                1 => (Link::Speed10Mbps, Duplex::Half),
+                2 => (Link::Speed10Mbps, Duplex::Full),
+                3 => (Link::Speed100Mbps, Duplex::Half),
+                4 => (Link::Speed100Mbps, Duplex::Full),
+                5 => (Link::Speed1Gbps, Duplex::Full),
+                6 => (Link::Speed10Gbps, Duplex::Full),
+                _ => (Link::Unknown, Duplex::Unknown),
             };
 
-            let stat = SpeedStat { port_no, link };
+            let stat = SpeedStat {
+                port_no,
+                link,
+                duplex,
+            };
             stats.push(stat);
         }
 
         Ok(SpeedStats { stats })
     }
+
+    /// Checks that every port number is unique and within `1..=port_count`.
+    pub fn validate(&self, port_count: &PortCount) -> Result<(), Error> {
+        validate_port_no(self.stats.iter().map(|x| x.port_no), port_count.count)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// SwitchInfo
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Identity of a switch.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SwitchInfo {
+    pub model: String,
+    pub name: String,
+    pub mac: String,
+    pub firmware: String,
+}
+
+impl SwitchInfo {
+    /// Decodes a reply of the query.
+    pub fn decode(dat: &[u8]) -> Result<Self, Error> {
+        let dat = QueryResponse::decode(dat)?;
+        let mut info = SwitchInfo::default();
+        for (cmd, d) in dat {
+            let text = || {
+                String::from_utf8_lossy(&d)
+                    .trim_end_matches('\0')
+                    .trim()
+                    .to_string()
+            };
+            match cmd {
+                x if x == Cmd::Model.tag() => info.model = text(),
+                x if x == Cmd::Name.tag() => info.name = text(),
+                x if x == Cmd::FirmwareVersion.tag() => info.firmware = text(),
+                x if x == Cmd::Mac.tag() => {
+                    let mac: Vec<String> = d.iter().map(|x| format!("{:02x}", x)).collect();
+                    info.mac = mac.join(":");
+                }
+                _ => (),
+            }
+        }
+
+        if info.model.is_empty() {
+            return Err(format_err!("failed to parse: model name is missing"));
+        }
+
+        Ok(info)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Capabilities
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// A query which may or may not be supported by a switch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Capability {
+    PortCount,
+    PortStat,
+    SpeedStat,
+}
+
+impl Capability {
+    /// All queries known by this crate.
+    pub const ALL: [Capability; 3] = [
+        Capability::PortCount,
+        Capability::PortStat,
+        Capability::SpeedStat,
+    ];
+
+    fn cmd(self) -> Cmd {
+        match self {
+            Capability::PortCount => Cmd::PortCount,
+            Capability::PortStat => Cmd::PortStat,
+            Capability::SpeedStat => Cmd::SpeedStat,
+        }
+    }
+}
+
+/// Specification of a tested model.
+struct Profile {
+    model: &'static str,
+    ports: u8,
+    max_link: Link,
+}
+
+static PROFILES: &[Profile] = &[
+    Profile {
+        model: "XS708E",
+        ports: 8,
+        max_link: Link::Speed10Gbps,
+    },
+    Profile {
+        model: "GS308E",
+        ports: 8,
+        max_link: Link::Speed1Gbps,
+    },
+    Profile {
+        model: "GS116Ev2",
+        ports: 16,
+        max_link: Link::Speed1Gbps,
+    },
+    Profile {
+        model: "GS108Ev3",
+        ports: 8,
+        max_link: Link::Speed1Gbps,
+    },
+    Profile {
+        model: "GS105Ev2",
+        ports: 5,
+        max_link: Link::Speed1Gbps,
+    },
+    Profile {
+        model: "GS108PEv3",
+        ports: 8,
+        max_link: Link::Speed1Gbps,
+    },
+    Profile {
+        model: "JGS524PE",
+        ports: 24,
+        max_link: Link::Speed1Gbps,
+    },
+];
+
+/// Set of queries supported by a switch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    supported: Vec<Capability>,
+    port_count: Option<PortCount>,
+    max_link: Option<Link>,
+}
+
+impl Capabilities {
+    /// Assumes that every query is supported.
+    pub fn all() -> Self {
+        Capabilities {
+            supported: Capability::ALL.to_vec(),
+            port_count: None,
+            max_link: None,
+        }
+    }
+
+    /// Returns the built-in profile of the model, if any.
+    ///
+    /// Every query is supported, and the replies are checked against the number of ports and the maximum link speed
+    /// of the model.
+    pub fn from_profile(model: &str) -> Option<Self> {
+        PROFILES
+            .iter()
+            .find(|x| x.model.eq_ignore_ascii_case(model.trim()))
+            .map(|x| Capabilities {
+                supported: Capability::ALL.to_vec(),
+                port_count: Some(PortCount { count: x.ports }),
+                max_link: Some(x.max_link),
+            })
+    }
+
+    /// Returns whether the query is supported.
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.supported.contains(&capability)
+    }
+
+    /// Returns the number of ports given by the profile of the model.
+    pub fn port_count(&self) -> Option<PortCount> {
+        self.port_count.clone()
+    }
+
+    /// Checks that the switch has no more ports than the model.
+    pub fn validate_port_count(&self, count: &PortCount) -> Result<(), Error> {
+        match self.port_count {
+            Some(ref max) if count.count > max.count => Err(format_err!(
+                "invalid port count: {} exceeds {} ports of the model",
+                count.count,
+                max.count
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that no link is faster than the model supports.
+    pub fn validate(&self, stats: &SpeedStats) -> Result<(), Error> {
+        let max = match self.max_link {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        for x in &stats.stats {
+            if x.link.mbps() > max.mbps() {
+                return Err(format_err!(
+                    "invalid link of port {}: {} Mbps exceeds {} Mbps of the model",
+                    x.port_no,
+                    x.link.mbps(),
+                    max.mbps()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_supported(dat: &[u8], capability: Capability) -> Result<bool, Error> {
+        let dat = QueryResponse::decode(dat)?;
+        let tag = capability.cmd().tag();
+        Ok(dat.iter().any(|(x, d)| *x == tag && !d.is_empty()))
+    }
 }
 
 // ---------------------------------------------------------------------------------------------------------------------
 // ProSafeSwitch
 // ---------------------------------------------------------------------------------------------------------------------
 
+/// Future returned by the asynchronous methods of [`ProSafeSwitch`].
+///
+/// Dropping it cancels the query.
+pub type SwitchFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+/// A client of a switch supported by ProSAFE Plus utility.
+///
+/// The asynchronous methods ( e.g. [`ProSafeSwitch::port_stat_async`] ) must be polled on a tokio runtime,
+/// and fail if the reply is not received until the given deadline.
+/// The blocking methods ( e.g. [`ProSafeSwitch::port_stat`] ) run them on a temporary runtime with the timeout
+/// of the client, so they must not be called from a tokio runtime.
+#[derive(Clone)]
 pub struct ProSafeSwitch {
     hostname: String,
-    if_name: String,
+    transport: Arc<dyn Transport>,
+    connector: Connector,
     timeout: Duration,
+    retries: u32,
 }
 
 impl ProSafeSwitch {
+    /// Creates a client which accesses `hostname` through the network interface `if_name`.
     pub fn new(hostname: &str, if_name: &str) -> Self {
+        ProSafeSwitch::with_connector(hostname, if_name, udp_connector())
+    }
+
+    /// Creates a client which accesses the switch through `transport`.
+    ///
+    /// [`ProSafeSwitch::find_iface`] returns the first network interface in the order of the names because any
+    /// interface uses `transport`.
+    pub fn with_transport(hostname: &str, transport: Arc<dyn Transport>) -> Self {
+        let connector_transport = Arc::clone(&transport);
         ProSafeSwitch {
             hostname: String::from(hostname),
-            if_name: String::from(if_name),
+            transport,
+            connector: Arc::new(move |_, _| Arc::clone(&connector_transport)),
             timeout: Duration::new(1, 0),
+            retries: 0,
         }
     }
 
-    fn request(
-        hostname: &str,
-        if_name: &str,
-        timeout: &Duration,
-        cmd: Cmd,
-    ) -> Result<Vec<u8>, Error> {
-        let iface = Interface::get_by_name(if_name)?
-            .ok_or_else(|| format_err!("failed to get network interface '{}'", if_name))?;
+    /// Creates a client which accesses the switch through the transport created by `connector`.
+    ///
+    /// [`ProSafeSwitch::find_iface`] creates the transports of the other interfaces by `connector` too.
+    pub fn with_connector(hostname: &str, if_name: &str, connector: Connector) -> Self {
+        ProSafeSwitch {
+            hostname: String::from(hostname),
+            transport: connector(hostname, if_name),
+            connector,
+            timeout: Duration::new(1, 0),
+            retries: 0,
+        }
+    }
 
-        let req = QueryRequest::new(cmd, iface.hardware_addr()?, HardwareAddr::zero());
-        let req = req.encode()?;
+    /// Returns the hostname of the switch.
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
 
-        let socket = UdpSocket::bind("0.0.0.0:63321")?;
-        let _ = socket.set_read_timeout(Some(*timeout));
+    /// Returns the NSDP port of the host bound by the exchanges with the switch. See [`Transport::host_port`].
+    pub fn host_port(&self) -> Option<u16> {
+        self.transport.host_port()
+    }
 
-        let sw_addr = format!("{}:{}", hostname, 63322)
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap();
-        socket.send_to(&req, sw_addr)?;
+    /// Returns the timeout of each query.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
 
-        let mut buf = [0; 1308];
-        let (_len, _src_addr) = socket.recv_from(&mut buf)?;
+    /// Sets the timeout of each query. The default is 1 second.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Returns the number of retries of each query.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Sets the number of retries of each query which failed within the timeout. The default is 0.
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    fn request(&self, cmd: &[Cmd], deadline: Instant) -> SwitchFuture<Vec<u8>> {
+        let sw = self.clone();
+        let cmd = cmd.to_vec();
+        Box::new(future::loop_fn(0, move |retry| {
+            let req = sw
+                .transport
+                .host_mac()
+                .and_then(|mac| QueryRequest::new(&cmd, mac, [0; 6]).encode());
+            let req = match req {
+                Ok(x) => x,
+                Err(x) => return future::Either::A(future::err(x)),
+            };
+            let retries = sw.retries;
+            let exchange = sw
+                .transport
+                .exchange(req, deadline.min(Instant::now() + sw.timeout));
+            future::Either::B(exchange.then(move |x| match x {
+                Ok(x) => Ok(Loop::Break(x)),
+                Err(_) if retry < retries && Instant::now() < deadline => {
+                    Ok(Loop::Continue(retry + 1))
+                }
+                Err(x) => Err(x),
+            }))
+        }))
+    }
+
+    fn block_on<T>(future: SwitchFuture<T>) -> Result<T, Error> {
+        let mut runtime = Runtime::new()?;
+        runtime.block_on(future)
+    }
 
-        Ok(Vec::from(&buf as &[u8]))
+    /// Returns the deadline of a query starting now, which allows the timeout for each retry.
+    pub fn deadline(&self) -> Instant {
+        Instant::now() + self.timeout * (self.retries + 1)
     }
 
+    /// Searches a network interface through which the switch is accessible.
     pub fn find_iface(&self) -> Result<String, Error> {
-        let ifaces =
-            Interface::get_all().map_err(|_| format_err!("failed to get network interfaces"))?;
-
-        for iface in ifaces {
-            let ret =
-                ProSafeSwitch::request(&self.hostname, &iface.name, &self.timeout, Cmd::PortStat)?;
-            let stat = PortStats::decode(&ret)?;
-            if stat.stats.len() > 0 {
-                return Ok(iface.name.clone());
+        ProSafeSwitch::block_on(self.find_iface_async(self.deadline()))
+    }
+
+    /// Asynchronous version of [`ProSafeSwitch::find_iface`].
+    ///
+    /// The interfaces are tried in the order of the names, and the first accessible one is returned.
+    /// Each interface is tried with the timeout and retries of the client, and the search is stopped at `deadline`.
+    pub fn find_iface_async(&self, deadline: Instant) -> SwitchFuture<String> {
+        match Interface::get_all() {
+            Ok(x) => {
+                self.find_iface_in_async(x.into_iter().map(|x| x.name.clone()).collect(), deadline)
             }
+            Err(_) => Box::new(future::err(format_err!("failed to get network interfaces"))),
         }
+    }
+
+    /// Searches the accessible one of the network interfaces `ifaces`. See [`ProSafeSwitch::find_iface_async`].
+    fn find_iface_in_async(
+        &self,
+        mut ifaces: Vec<String>,
+        deadline: Instant,
+    ) -> SwitchFuture<String> {
+        // The interfaces are returned in random order.
+        ifaces.sort();
 
-        Err(format_err!("failed to find accessible network interface"))
+        let sw = self.clone();
+        let timeout = self.timeout;
+        let retries = self.retries;
+        Box::new(future::loop_fn(ifaces.into_iter(), move |mut ifaces| {
+            let if_name = match ifaces.next() {
+                Some(x) => x,
+                None => {
+                    return future::Either::A(future::err(format_err!(
+                        "failed to find accessible network interface"
+                    )))
+                }
+            };
+            let mut sw =
+                ProSafeSwitch::with_connector(&sw.hostname, &if_name, Arc::clone(&sw.connector));
+            sw.timeout = timeout;
+            sw.retries = retries;
+            let iface_deadline = deadline.min(sw.deadline());
+            future::Either::B(
+                sw.port_stat_async(iface_deadline)
+                    .then(move |stat| match stat {
+                        Ok(ref stat) if !stat.stats.is_empty() => Ok(Loop::Break(if_name)),
+                        _ if Instant::now() >= deadline => {
+                            Err(format_err!("failed to find accessible network interface"))
+                        }
+                        _ => Ok(Loop::Continue(ifaces)),
+                    }),
+            )
+        }))
+    }
+
+    /// Queries model, name, MAC address and firmware version.
+    pub fn info(&self) -> Result<SwitchInfo, Error> {
+        ProSafeSwitch::block_on(self.info_async(self.deadline()))
+    }
+
+    /// Asynchronous version of [`ProSafeSwitch::info`].
+    pub fn info_async(&self, deadline: Instant) -> SwitchFuture<SwitchInfo> {
+        Box::new(
+            self.request(
+                &[Cmd::Model, Cmd::Name, Cmd::Mac, Cmd::FirmwareVersion],
+                deadline,
+            )
+            .and_then(|x| SwitchInfo::decode(&x)),
+        )
+    }
+
+    /// Queries each of [`Capability::ALL`] to find the supported ones.
+    pub fn probe_capabilities(&self) -> Result<Capabilities, Error> {
+        ProSafeSwitch::block_on(self.probe_capabilities_async(self.deadline()))
     }
 
+    /// Asynchronous version of [`ProSafeSwitch::probe_capabilities`].
+    pub fn probe_capabilities_async(&self, deadline: Instant) -> SwitchFuture<Capabilities> {
+        let sw = self.clone();
+        Box::new(
+            stream::iter_ok(Capability::ALL.to_vec())
+                .and_then(move |capability| {
+                    sw.request(&[capability.cmd()], deadline)
+                        .and_then(move |x| {
+                            if Capabilities::is_supported(&x, capability)? {
+                                Ok(Some(capability))
+                            } else {
+                                Ok(None)
+                            }
+                        })
+                })
+                .filter_map(|x| x)
+                .collect()
+                .map(|supported| Capabilities {
+                    supported,
+                    port_count: None,
+                    max_link: None,
+                }),
+        )
+    }
+
+    /// Queries traffic counters.
     pub fn port_stat(&self) -> Result<PortStats, Error> {
-        let ret =
-            ProSafeSwitch::request(&self.hostname, &self.if_name, &self.timeout, Cmd::PortStat)?;
-        Ok(PortStats::decode(&ret)?)
+        ProSafeSwitch::block_on(self.port_stat_async(self.deadline()))
+    }
+
+    /// Asynchronous version of [`ProSafeSwitch::port_stat`].
+    pub fn port_stat_async(&self, deadline: Instant) -> SwitchFuture<PortStats> {
+        Box::new(
+            self.request(&[Cmd::PortStat], deadline)
+                .and_then(|x| PortStats::decode(&x)),
+        )
+    }
+
+    /// Queries number of ports.
+    pub fn port_count(&self) -> Result<PortCount, Error> {
+        ProSafeSwitch::block_on(self.port_count_async(self.deadline()))
+    }
+
+    /// Asynchronous version of [`ProSafeSwitch::port_count`].
+    pub fn port_count_async(&self, deadline: Instant) -> SwitchFuture<PortCount> {
+        Box::new(
+            self.request(&[Cmd::PortCount], deadline)
+                .and_then(|x| PortCount::decode(&x)),
+        )
     }
 
+    /// Queries link status.
     pub fn speed_stat(&self) -> Result<SpeedStats, Error> {
-        let ret =
-            ProSafeSwitch::request(&self.hostname, &self.if_name, &self.timeout, Cmd::SpeedStat)?;
-        Ok(SpeedStats::decode(&ret)?)
+        ProSafeSwitch::block_on(self.speed_stat_async(self.deadline()))
+    }
+
+    /// Asynchronous version of [`ProSafeSwitch::speed_stat`].
+    pub fn speed_stat_async(&self, deadline: Instant) -> SwitchFuture<SpeedStats> {
+        Box::new(
+            self.request(&[Cmd::SpeedStat], deadline)
+                .and_then(|x| SpeedStats::decode(&x)),
+        )
     }
 }
 
@@ -320,11 +916,14 @@ impl ProSafeSwitch {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::transport::MockTransport;
     use hex_literal::*;
+    use proptest::arbitrary::any as arbitrary;
+    use proptest::prelude::{prop, prop_assert, prop_assert_eq, prop_assume, proptest};
 
     #[test]
     fn test_query_encode() {
-        let req = QueryRequest::new(Cmd::PortStat, HardwareAddr::zero(), HardwareAddr::zero());
+        let req = QueryRequest::new(&[Cmd::PortStat], [0; 6], [0; 6]);
         let dat = req.encode().unwrap();
         let expected = hex!(
             "010100000000000000000000000000000000000000000a0a4e5344500000000010000000ffff0000"
@@ -407,37 +1006,311 @@ mod tests {
                 SpeedStat {
                     port_no: 1,
                     link: Link::None,
+                    duplex: Duplex::None,
                 },
                 SpeedStat {
                     port_no: 2,
                     link: Link::Speed10Mbps,
+                    duplex: Duplex::Half,
                 },
                 SpeedStat {
                     port_no: 3,
                     link: Link::Speed10Mbps,
+                    duplex: Duplex::Full,
                 },
                 SpeedStat {
                     port_no: 4,
                     link: Link::Speed100Mbps,
+                    duplex: Duplex::Half,
                 },
                 SpeedStat {
                     port_no: 5,
                     link: Link::Speed100Mbps,
+                    duplex: Duplex::Full,
                 },
                 SpeedStat {
                     port_no: 6,
                     link: Link::Speed1Gbps,
+                    duplex: Duplex::Full,
                 },
                 SpeedStat {
                     port_no: 7,
                     link: Link::Speed10Gbps,
+                    duplex: Duplex::Full,
                 },
                 SpeedStat {
                     port_no: 8,
                     link: Link::Unknown,
+                    duplex: Duplex::Unknown,
                 },
             ],
         };
         assert_eq!(stat, expected);
     }
+
+    #[test]
+    fn test_port_count_decode() {
+        let dat = hex!(
+            "01020000000000000cc47a3a39a808bd436a1596000000804e534450000000006000000108ffff0000"
+        );
+        let count = PortCount::decode(&dat).unwrap();
+        assert_eq!(count, PortCount { count: 8 });
+    }
+
+    #[test]
+    fn test_port_stat_validate() {
+        let stat = |port_no| PortStat {
+            port_no,
+            recv_bytes: 0,
+            send_bytes: 0,
+            error_pkts: 0,
+        };
+        let count = PortCount { count: 8 };
+
+        let stats = PortStats {
+            stats: vec![stat(1), stat(2), stat(8)],
+        };
+        assert!(stats.validate(&count).is_ok());
+
+        let stats = PortStats {
+            stats: vec![stat(1), stat(0)],
+        };
+        assert!(stats.validate(&count).is_err());
+
+        let stats = PortStats {
+            stats: vec![stat(1), stat(9)],
+        };
+        assert!(stats.validate(&count).is_err());
+
+        let stats = PortStats {
+            stats: vec![stat(1), stat(2), stat(1)],
+        };
+        assert!(stats.validate(&count).is_err());
+    }
+
+    #[test]
+    fn test_query_encode_multi() {
+        let req = QueryRequest::new(
+            &[Cmd::Model, Cmd::Name, Cmd::Mac, Cmd::FirmwareVersion],
+            [0; 6],
+            [0; 6],
+        );
+        let dat = req.encode().unwrap();
+        assert_eq!(dat.len(), 32 + 4 * 5);
+        assert_eq!(
+            dat[32..],
+            hex!("000100000003000000040000000d0000ffff0000")[..]
+        );
+    }
+
+    #[test]
+    fn test_info_decode() {
+        let dat = hex!(
+            "01020000000000000cc47a3a39a808bd436a1596000000804e53445000000000000100084753313038457633000300067377697463680004000608bd436a1596000d0007312e30302e3130ffff0000"
+        );
+        let info = SwitchInfo::decode(&dat).unwrap();
+
+        let expected = SwitchInfo {
+            model: String::from("GS108Ev3"),
+            name: String::from("switch"),
+            mac: String::from("08:bd:43:6a:15:96"),
+            firmware: String::from("1.00.10"),
+        };
+        assert_eq!(info, expected);
+    }
+
+    #[test]
+    fn test_find_iface() {
+        let reply = hex!(
+            "01020000000000000cc47a3a39a808bd436a1596000000804e534450000000001000003101000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000ffff0000"
+        );
+        let transport = MockTransport::new(move |_| Some(reply.to_vec()));
+        let sw = ProSafeSwitch::with_transport("switch", Arc::new(transport));
+
+        // Any interface is accessible, so the first one in the order of the names is returned.
+        let ifaces = vec![
+            String::from("wlan0"),
+            String::from("eth1"),
+            String::from("eth0"),
+        ];
+        let found = ProSafeSwitch::block_on(sw.find_iface_in_async(ifaces, sw.deadline()));
+        assert_eq!(found.unwrap(), "eth0");
+        let found = ProSafeSwitch::block_on(sw.find_iface_in_async(Vec::new(), sw.deadline()));
+        assert!(found.is_err());
+    }
+
+    #[test]
+    fn test_find_iface_timeout() {
+        let reply = hex!(
+            "01020000000000000cc47a3a39a808bd436a1596000000804e534450000000001000003101000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000ffff0000"
+        );
+        let silent: Arc<dyn Transport> = Arc::new(MockTransport::new(|_| None));
+        let replying: Arc<dyn Transport> =
+            Arc::new(MockTransport::new(move |_| Some(reply.to_vec())));
+        let connector: Connector = Arc::new(move |_, if_name| match if_name {
+            "eth0" => Arc::clone(&silent),
+            _ => Arc::clone(&replying),
+        });
+        let mut sw = ProSafeSwitch::with_connector("switch", "eth0", connector);
+        sw.set_timeout(Duration::from_millis(50));
+
+        // The first interface times out, and the search continues until the overall deadline.
+        let ifaces = vec![String::from("eth0"), String::from("eth1")];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let found = ProSafeSwitch::block_on(sw.find_iface_in_async(ifaces, deadline));
+        assert_eq!(found.unwrap(), "eth1");
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let caps = Capabilities::from_profile("GS108Ev3").unwrap();
+        assert!(caps.supports(Capability::PortStat));
+        assert!(caps.supports(Capability::PortCount));
+        assert_eq!(caps.port_count(), Some(PortCount { count: 8 }));
+        assert_eq!(
+            Capabilities::from_profile("GS116Ev2").unwrap().port_count(),
+            Some(PortCount { count: 16 })
+        );
+        assert!(Capabilities::from_profile("XX999").is_none());
+        assert_eq!(Capabilities::all().port_count(), None);
+        assert!(caps.validate_port_count(&PortCount { count: 8 }).is_ok());
+        assert!(caps.validate_port_count(&PortCount { count: 5 }).is_ok());
+        assert!(caps.validate_port_count(&PortCount { count: 9 }).is_err());
+        assert!(Capabilities::all()
+            .validate_port_count(&PortCount { count: 9 })
+            .is_ok());
+
+        let stats = |link| SpeedStats {
+            stats: vec![SpeedStat {
+                port_no: 1,
+                link,
+                duplex: Duplex::Full,
+            }],
+        };
+        assert!(caps.validate(&stats(Link::Speed1Gbps)).is_ok());
+        assert!(caps.validate(&stats(Link::Speed10Gbps)).is_err());
+        let caps = Capabilities::from_profile("XS708E").unwrap();
+        assert!(caps.validate(&stats(Link::Speed10Gbps)).is_ok());
+        assert!(Capabilities::all()
+            .validate(&stats(Link::Speed10Gbps))
+            .is_ok());
+
+        let supported = hex!(
+            "01020000000000000cc47a3a39a808bd436a1596000000804e534450000000006000000108ffff0000"
+        );
+        let unsupported = hex!(
+            "01020000000000000cc47a3a39a808bd436a1596000000804e5344500000000060000000ffff0000"
+        );
+        assert!(Capabilities::is_supported(&supported, Capability::PortCount).unwrap());
+        assert!(!Capabilities::is_supported(&unsupported, Capability::PortCount).unwrap());
+    }
+
+    #[test]
+    fn test_response_encode() {
+        let req = QueryRequest::new(&[Cmd::PortCount, Cmd::Model], [0; 6], [0; 6]);
+        let req = req.encode().unwrap();
+        let tags = QueryResponse::decode_request(&req).unwrap();
+        assert_eq!(tags, vec![0x6000, 0x0001]);
+
+        let records = vec![(0x6000, vec![8]), (0x0001, b"GS108Ev3".to_vec())];
+        let dat = QueryResponse::encode(&req, [0; 6], &records);
+        assert_eq!(PortCount::decode(&dat).unwrap(), PortCount { count: 8 });
+        assert_eq!(QueryResponse::decode(&dat).unwrap(), records);
+    }
+
+    fn packet(records: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let req = QueryRequest::new(&[], [0; 6], [0; 6]).encode().unwrap();
+        QueryResponse::encode(&req, [0; 6], records)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_response_roundtrip(
+            records in prop::collection::vec(
+                (0u16..0xffff, prop::collection::vec(arbitrary::<u8>(), 0..64)),
+                0..16,
+            )
+        ) {
+            let dat = packet(&records);
+            prop_assert_eq!(QueryResponse::decode(&dat).unwrap(), records);
+        }
+
+        #[test]
+        fn prop_port_stat_roundtrip(
+            stats in prop::collection::vec((arbitrary::<u8>(), arbitrary::<u64>(), arbitrary::<u64>(), arbitrary::<u64>()), 0..32)
+        ) {
+            let records: Vec<_> = stats
+                .iter()
+                .map(|(port_no, recv, send, error)| {
+                    let mut dat = vec![*port_no];
+                    for x in [*recv, *send, 0u64, 0, 0, *error].iter() {
+                        dat.extend_from_slice(&x.to_be_bytes());
+                    }
+                    (Cmd::PortStat.tag(), dat)
+                })
+                .collect();
+            let expected: Vec<_> = stats
+                .iter()
+                .map(|(port_no, recv_bytes, send_bytes, error_pkts)| PortStat {
+                    port_no: *port_no,
+                    recv_bytes: *recv_bytes,
+                    send_bytes: *send_bytes,
+                    error_pkts: *error_pkts,
+                })
+                .collect();
+            prop_assert_eq!(PortStats::decode(&packet(&records)).unwrap().stats, expected);
+        }
+
+        #[test]
+        fn prop_truncated(
+            records in prop::collection::vec(
+                (0u16..0xffff, prop::collection::vec(arbitrary::<u8>(), 0..64)),
+                1..16,
+            ),
+            cut in arbitrary::<prop::sample::Index>(),
+        ) {
+            let dat = packet(&records);
+            let dat = &dat[..cut.index(dat.len())];
+            if let Ok(x) = QueryResponse::decode(dat) {
+                prop_assert!(records.starts_with(&x));
+            }
+        }
+
+        #[test]
+        fn prop_oversized_length(len in 1u16..=0xffff, body in prop::collection::vec(arbitrary::<u8>(), 0..64)) {
+            prop_assume!(usize::from(len) > body.len());
+            let mut dat = packet(&[]);
+            dat.truncate(32);
+            dat.extend_from_slice(&Cmd::PortStat.tag().to_be_bytes());
+            dat.extend_from_slice(&len.to_be_bytes());
+            dat.extend_from_slice(&body);
+            prop_assert!(QueryResponse::decode(&dat).is_err());
+        }
+
+        #[test]
+        fn prop_unknown_tag(tag in 0u16..0xffff, body in prop::collection::vec(arbitrary::<u8>(), 0..64)) {
+            prop_assume!(tag != Cmd::PortStat.tag() && tag != Cmd::SpeedStat.tag());
+            let dat = packet(&[(tag, body)]);
+            prop_assert!(PortStats::decode(&dat).unwrap().stats.is_empty());
+            prop_assert!(SpeedStats::decode(&dat).unwrap().stats.is_empty());
+        }
+
+        #[test]
+        fn prop_arbitrary(dat in prop::collection::vec(arbitrary::<u8>(), 0..512), header in arbitrary::<bool>()) {
+            let dat = if header {
+                let mut x = packet(&[]);
+                x.truncate(32);
+                x.extend_from_slice(&dat);
+                x
+            } else {
+                dat
+            };
+            let _ = QueryResponse::decode(&dat);
+            let _ = QueryResponse::decode_request(&dat);
+            let _ = PortStats::decode(&dat);
+            let _ = SpeedStats::decode(&dat);
+            let _ = PortCount::decode(&dat);
+            let _ = SwitchInfo::decode(&dat);
+        }
+    }
 }
@@ -0,0 +1,391 @@
+use crate::prosafe_switch::{Cmd, QueryResponse};
+use failure::format_err;
+use failure::Error;
+use futures::future::{self, Loop};
+use futures::Future;
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::timer::Delay;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Config
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Virtual switches described by TOML.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SimulatorConfig {
+    #[serde(default, rename = "switch")]
+    pub switches: Vec<SwitchConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SwitchConfig {
+    /// Address on which NSDP requests are received.
+    pub listen: String,
+    /// Number of switches. The copies listen on the consecutive IP addresses.
+    pub count: u32,
+    pub model: String,
+    pub name: String,
+    pub mac: String,
+    pub firmware: String,
+    pub ports: u8,
+    /// Tags which are answered with empty values.
+    pub unsupported: Vec<u16>,
+    /// Probability of dropping a request.
+    pub loss: f64,
+    /// Delay of each reply in milliseconds.
+    pub latency: u64,
+    /// Probability of truncating a reply.
+    pub malformed: f64,
+    #[serde(rename = "port")]
+    pub port_configs: Vec<PortConfig>,
+}
+
+impl Default for SwitchConfig {
+    fn default() -> Self {
+        SwitchConfig {
+            listen: String::from("127.0.0.1:63322"),
+            count: 1,
+            model: String::from("GS108Ev3"),
+            name: String::from("prosafe-sim"),
+            mac: String::from("02:00:00:00:00:01"),
+            firmware: String::from("1.00.00"),
+            ports: 8,
+            unsupported: Vec::new(),
+            loss: 0.0,
+            latency: 0,
+            malformed: 0.0,
+            port_configs: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PortConfig {
+    pub no: u8,
+    /// Link speed: `down`, `10M`, `100M`, `1G`, `10G` or a raw link code.
+    pub link: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub errors: u64,
+    /// Increase of `rx_bytes` per second.
+    pub rx_rate: u64,
+    /// Increase of `tx_bytes` per second.
+    pub tx_rate: u64,
+    /// Increase of `errors` per second.
+    pub error_rate: u64,
+    /// Period of link flaps in seconds. `0` disables flaps.
+    pub flap_period: u64,
+    /// Duration of link down in each period in seconds.
+    pub flap_down: u64,
+}
+
+impl Default for PortConfig {
+    fn default() -> Self {
+        PortConfig {
+            no: 0,
+            link: String::from("1G"),
+            rx_bytes: 0,
+            tx_bytes: 0,
+            errors: 0,
+            rx_rate: 0,
+            tx_rate: 0,
+            error_rate: 0,
+            flap_period: 0,
+            flap_down: 0,
+        }
+    }
+}
+
+impl PortConfig {
+    fn link_code(&self) -> Result<u8, Error> {
+        match self.link.as_str() {
+            "down" => Ok(0),
+            "10M" => Ok(2),
+            "100M" => Ok(4),
+            "1G" => Ok(5),
+            "10G" => Ok(6),
+            x => x
+                .parse()
+                .map_err(|_| format_err!("invalid link of port{}: '{}'", self.no, x)),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// VirtualSwitch
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// A switch answering NSDP requests from its config.
+pub struct VirtualSwitch {
+    config: SwitchConfig,
+    mac: [u8; 6],
+    links: Vec<u8>,
+    started: Instant,
+}
+
+impl VirtualSwitch {
+    pub fn new(config: SwitchConfig) -> Result<Self, Error> {
+        let mut mac = [0; 6];
+        let octets: Vec<&str> = config.mac.split(':').collect();
+        if octets.len() != 6 {
+            return Err(format_err!("invalid mac: '{}'", config.mac));
+        }
+        for (x, y) in mac.iter_mut().zip(octets) {
+            *x = u8::from_str_radix(y, 16)
+                .map_err(|_| format_err!("invalid mac: '{}'", config.mac))?;
+        }
+
+        for p in [config.loss, config.malformed].iter() {
+            if !(0.0..=1.0).contains(p) {
+                return Err(format_err!("invalid probability: {}", p));
+            }
+        }
+
+        let mut links = Vec::new();
+        for port in &config.port_configs {
+            if port.no == 0 || port.no > config.ports {
+                return Err(format_err!("invalid port number: {}", port.no));
+            }
+            links.push(port.link_code()?);
+        }
+
+        Ok(VirtualSwitch {
+            config,
+            mac,
+            links,
+            started: Instant::now(),
+        })
+    }
+
+    /// Returns the reply to `request` at `elapsed` since the start, ignoring loss and malformation.
+    pub fn answer(&self, request: &[u8], elapsed: Duration) -> Result<Vec<u8>, Error> {
+        let secs = elapsed.as_secs();
+        let tags = QueryResponse::decode_request(request)?;
+        let mut records = Vec::new();
+        for tag in tags {
+            if self.config.unsupported.contains(&tag) {
+                records.push((tag, Vec::new()));
+                continue;
+            }
+            match tag {
+                x if x == Cmd::Model.tag() => {
+                    records.push((x, self.config.model.as_bytes().to_vec()))
+                }
+                x if x == Cmd::Name.tag() => {
+                    records.push((x, self.config.name.as_bytes().to_vec()))
+                }
+                x if x == Cmd::Mac.tag() => records.push((x, self.mac.to_vec())),
+                x if x == Cmd::FirmwareVersion.tag() => {
+                    records.push((x, self.config.firmware.as_bytes().to_vec()))
+                }
+                x if x == Cmd::PortCount.tag() => records.push((x, vec![self.config.ports])),
+                x if x == Cmd::PortStat.tag() => {
+                    for port_no in 1..=self.config.ports {
+                        let mut dat = vec![port_no];
+                        let (rx, tx, errors) = match self.port(port_no) {
+                            Some((port, _)) => (
+                                port.rx_bytes.wrapping_add(port.rx_rate.wrapping_mul(secs)),
+                                port.tx_bytes.wrapping_add(port.tx_rate.wrapping_mul(secs)),
+                                port.errors.wrapping_add(port.error_rate.wrapping_mul(secs)),
+                            ),
+                            None => (0, 0, 0),
+                        };
+                        for v in [rx, tx, 0, 0, 0, errors].iter() {
+                            dat.extend_from_slice(&v.to_be_bytes());
+                        }
+                        records.push((x, dat));
+                    }
+                }
+                x if x == Cmd::SpeedStat.tag() => {
+                    for port_no in 1..=self.config.ports {
+                        let link = match self.port(port_no) {
+                            Some((port, _))
+                                if port.flap_period > 0
+                                    && secs % port.flap_period < port.flap_down =>
+                            {
+                                0
+                            }
+                            Some((_, link)) => link,
+                            None => 0,
+                        };
+                        records.push((x, vec![port_no, link, 1]));
+                    }
+                }
+                x => records.push((x, Vec::new())),
+            }
+        }
+
+        Ok(QueryResponse::encode(request, self.mac, &records))
+    }
+
+    /// Returns the reply to `request`, or `None` if the request is lost.
+    pub fn reply(&self, request: &[u8]) -> Option<Vec<u8>> {
+        if rand::random_bool(self.config.loss) {
+            return None;
+        }
+        let mut reply = self.answer(request, self.started.elapsed()).ok()?;
+        if rand::random_bool(self.config.malformed) {
+            let len = rand::random_range(0..reply.len());
+            reply.truncate(len);
+        }
+        Some(reply)
+    }
+
+    fn port(&self, port_no: u8) -> Option<(&PortConfig, u8)> {
+        self.config
+            .port_configs
+            .iter()
+            .zip(self.links.iter())
+            .find(|(x, _)| x.no == port_no)
+            .map(|(x, y)| (x, *y))
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Simulator
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// UDP server of virtual switches.
+pub struct Simulator;
+
+impl Simulator {
+    /// Binds all switches of `config` and returns the future serving them.
+    pub fn serve(
+        config: &SimulatorConfig,
+        verbose: bool,
+    ) -> Result<Box<dyn Future<Item = (), Error = ()> + Send>, Error> {
+        let mut servers = Vec::new();
+        for switch in &config.switches {
+            let listen: SocketAddr = switch
+                .listen
+                .parse()
+                .map_err(|_| format_err!("invalid listen address: '{}'", switch.listen))?;
+            for i in 0..switch.count {
+                let addr = SocketAddr::new(Simulator::offset(listen.ip(), i)?, listen.port());
+                let mut sw = VirtualSwitch::new(switch.clone())?;
+                if switch.count > 1 {
+                    sw.config.name = format!("{}-{}", switch.name, i + 1);
+                    let mut mac = [0; 8];
+                    mac[2..].copy_from_slice(&sw.mac);
+                    let mac = u64::from_be_bytes(mac).wrapping_add(u64::from(i));
+                    sw.mac.copy_from_slice(&mac.to_be_bytes()[2..]);
+                }
+
+                let socket = UdpSocket::bind(&addr)?;
+                if verbose {
+                    println!("Switch started: {} ({})", addr, sw.config.name);
+                }
+                servers.push(Simulator::run(socket, sw, verbose));
+            }
+        }
+
+        Ok(Box::new(future::join_all(servers).map(|_| ())))
+    }
+
+    fn offset(ip: IpAddr, i: u32) -> Result<IpAddr, Error> {
+        match ip {
+            IpAddr::V4(x) => u32::from(x)
+                .checked_add(i)
+                .map(|x| IpAddr::V4(x.into()))
+                .ok_or_else(|| format_err!("too many switches from {}", ip)),
+            IpAddr::V6(_) if i == 0 => Ok(ip),
+            IpAddr::V6(_) => Err(format_err!("count is not supported for IPv6: {}", ip)),
+        }
+    }
+
+    fn run(
+        socket: UdpSocket,
+        sw: VirtualSwitch,
+        verbose: bool,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let latency = Duration::from_millis(sw.config.latency);
+        Box::new(
+            future::loop_fn((socket, sw), move |(socket, sw)| {
+                socket
+                    .recv_dgram(vec![0; 1500])
+                    .map_err(Error::from)
+                    .and_then(move |(socket, buf, len, src)| {
+                        let reply = sw.reply(&buf[..len]);
+                        if verbose {
+                            println!("Request from {}: replied: {}", src, reply.is_some());
+                        }
+                        let delay = Delay::new(Instant::now() + latency).map_err(Error::from);
+                        delay.and_then(move |_| match reply {
+                            Some(reply) => future::Either::A(
+                                socket
+                                    .send_dgram(reply, &src)
+                                    .map(move |(socket, _)| Loop::Continue((socket, sw)))
+                                    .map_err(Error::from),
+                            ),
+                            None => future::Either::B(future::ok(Loop::Continue((socket, sw)))),
+                        })
+                    })
+            })
+            .map_err(|x: Error| eprintln!("Simulator error: {}", x)),
+        )
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prosafe_switch::{Link, ProSafeSwitch};
+    use crate::transport::MockTransport;
+    use std::sync::Arc;
+
+    fn switch(elapsed: Duration) -> ProSafeSwitch {
+        let config: SimulatorConfig = toml::from_str(
+            r#"
+            [[switch]]
+            model = "XS708E"
+            ports = 8
+            unsupported = [0x6000]
+
+            [[switch.port]]
+            no = 2
+            link = "10G"
+            rx_bytes = 100
+            rx_rate = 10
+            flap_period = 60
+            flap_down = 5
+            "#,
+        )
+        .unwrap();
+        let sw = VirtualSwitch::new(config.switches[0].clone()).unwrap();
+        let transport = MockTransport::new(move |x| sw.answer(x, elapsed).ok());
+        ProSafeSwitch::with_transport("sim", Arc::new(transport))
+    }
+
+    #[test]
+    fn test_answer() {
+        let sw = switch(Duration::from_secs(10));
+        assert_eq!(sw.info().unwrap().model, "XS708E");
+
+        let stats = sw.port_stat().unwrap();
+        assert_eq!(stats.stats.len(), 8);
+        assert_eq!(stats.stats[1].recv_bytes, 200);
+
+        let stats = sw.speed_stat().unwrap();
+        assert_eq!(stats.stats[0].link, Link::None);
+        assert_eq!(stats.stats[1].link, Link::Speed10Gbps);
+
+        let caps = sw.probe_capabilities().unwrap();
+        assert!(!caps.supports(crate::prosafe_switch::Capability::PortCount));
+    }
+
+    #[test]
+    fn test_flap() {
+        let sw = switch(Duration::from_secs(62));
+        let stats = sw.speed_stat().unwrap();
+        assert_eq!(stats.stats[1].link, Link::None);
+    }
+}
@@ -0,0 +1,632 @@
+use crate::prosafe_switch::SwitchFuture;
+use failure::format_err;
+use failure::Error;
+use futures::sync::oneshot;
+use futures::{future, try_ready, Async, Future};
+use interfaces::Interface;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::timer::{timeout, Delay, Timeout};
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Transport
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// A way to exchange NSDP packets with a switch.
+pub trait Transport: Send + Sync {
+    /// Returns the MAC address put into requests as the source.
+    fn host_mac(&self) -> Result<[u8; 6], Error>;
+
+    /// Sends `request` and receives the reply until `deadline`.
+    fn exchange(&self, request: Vec<u8>, deadline: Instant) -> SwitchFuture<Vec<u8>>;
+
+    /// Returns the NSDP port of the host bound by each exchange, through which exchanges can't run at once.
+    ///
+    /// The default is `None`, which means that the exchanges of the transport don't bind a port of the host.
+    fn host_port(&self) -> Option<u16> {
+        None
+    }
+}
+
+/// Creates the transport to `hostname` through the network interface `if_name`.
+pub type Connector = Arc<dyn Fn(&str, &str) -> Arc<dyn Transport> + Send + Sync>;
+
+/// Returns the connector creating [`UdpTransport`].
+pub fn udp_connector() -> Connector {
+    udp_connector_with(UdpOptions::default())
+}
+
+/// Returns the connector creating [`UdpTransport`] with `options`.
+pub fn udp_connector_with(options: UdpOptions) -> Connector {
+    Arc::new(move |hostname, if_name| {
+        Arc::new(UdpTransport::with_options(hostname, if_name, options))
+    })
+}
+
+fn timed_out(hostname: &str) -> Error {
+    format_err!("timed out waiting for reply from {}", hostname)
+}
+
+fn timeout_error<E: Into<Error> + std::fmt::Display>(
+    hostname: &str,
+    x: timeout::Error<E>,
+) -> Error {
+    if x.is_elapsed() {
+        timed_out(hostname)
+    } else if x.is_inner() {
+        x.into_inner().unwrap().into()
+    } else {
+        format_err!("timer error: {}", x)
+    }
+}
+
+fn iface_mac(if_name: &str) -> Result<[u8; 6], Error> {
+    let iface = Interface::get_by_name(if_name)?
+        .ok_or_else(|| format_err!("failed to get network interface '{}'", if_name))?;
+    let mut mac = [0; 6];
+    mac.copy_from_slice(iface.hardware_addr()?.as_bytes());
+    Ok(mac)
+}
+
+fn resolve(hostname: &str, port: u16) -> Result<SocketAddr, Error> {
+    format!("{}:{}", hostname, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format_err!("failed to resolve '{}'", hostname))
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// UdpTransport
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Transport through a network interface of the host.
+pub struct UdpTransport {
+    hostname: String,
+    if_name: String,
+    options: UdpOptions,
+}
+
+/// Addresses used by [`UdpTransport`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct UdpOptions {
+    /// Address to which the socket of the host is bound. The default is `0.0.0.0`.
+    pub source_address: IpAddr,
+    /// NSDP port of the host. The default is 63321.
+    pub host_port: u16,
+    /// NSDP port of the switch. The default is 63322.
+    pub switch_port: u16,
+}
+
+impl Default for UdpOptions {
+    fn default() -> Self {
+        UdpOptions {
+            source_address: IpAddr::from([0, 0, 0, 0]),
+            host_port: 63321,
+            switch_port: 63322,
+        }
+    }
+}
+
+impl UdpTransport {
+    pub fn new(hostname: &str, if_name: &str) -> Self {
+        UdpTransport::with_options(hostname, if_name, UdpOptions::default())
+    }
+
+    pub fn with_options(hostname: &str, if_name: &str, options: UdpOptions) -> Self {
+        UdpTransport {
+            hostname: String::from(hostname),
+            if_name: String::from(if_name),
+            options,
+        }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn host_mac(&self) -> Result<[u8; 6], Error> {
+        iface_mac(&self.if_name)
+    }
+
+    fn exchange(&self, request: Vec<u8>, deadline: Instant) -> SwitchFuture<Vec<u8>> {
+        let prepare = || -> Result<_, Error> {
+            let socket = UdpSocket::bind(&SocketAddr::new(
+                self.options.source_address,
+                self.options.host_port,
+            ))?;
+
+            let sw_addr = resolve(&self.hostname, self.options.switch_port)?;
+
+            Ok((socket, sw_addr))
+        };
+
+        let (socket, sw_addr) = match prepare() {
+            Ok(x) => x,
+            Err(x) => return Box::new(future::err(x)),
+        };
+
+        let exchange = socket
+            .send_dgram(request, &sw_addr)
+            .and_then(|(socket, _)| socket.recv_dgram(vec![0; 1308]))
+            .map(|(_, mut buf, len, _)| {
+                buf.truncate(len);
+                buf
+            });
+
+        let hostname = self.hostname.clone();
+        Box::new(Timeout::new_at(exchange, deadline).map_err(move |x| timeout_error(&hostname, x)))
+    }
+
+    fn host_port(&self) -> Option<u16> {
+        // Port 0 binds an ephemeral port at each exchange.
+        Some(self.options.host_port).filter(|x| *x != 0)
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// UdpMux
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Socket of the host shared by concurrent exchanges with switches.
+///
+/// Switches reply to the fixed NSDP port of the host, so [`UdpTransport`] can't exchange with two switches at once.
+/// The transports created by [`UdpMux::connector`] send requests through one socket, and the replies are dispatched
+/// by the address of the switch and the sequence number echoed from the request. The mux gives each request its own
+/// sequence number, and restores the original one in the reply. The socket is bound at the first exchange, and the receiving task
+/// is spawned on the runtime running it. So the mux must be used on one runtime which outlives the mux.
+pub struct UdpMux {
+    options: UdpOptions,
+    state: Arc<Mutex<MuxState>>,
+}
+
+/// Address of the switch and the sequence number by which a reply is waited.
+type WaiterKey = (SocketAddr, u16);
+
+#[derive(Default)]
+struct MuxState {
+    socket: Option<Arc<Mutex<UdpSocket>>>,
+    waiters: HashMap<WaiterKey, (u64, oneshot::Sender<Vec<u8>>)>,
+    next_id: u64,
+    next_seq: u16,
+}
+
+impl MuxState {
+    /// Returns a sequence number which isn't waited from `sw_addr`.
+    fn allocate_seq(&mut self, sw_addr: SocketAddr) -> Option<u16> {
+        for _ in 0..=u16::MAX as u32 {
+            let seq = self.next_seq;
+            self.next_seq = self.next_seq.wrapping_add(1);
+            if !self.waiters.contains_key(&(sw_addr, seq)) {
+                return Some(seq);
+            }
+        }
+        None
+    }
+}
+
+/// Removes the waiter when the exchange is finished or dropped.
+struct MuxWaiter {
+    state: Arc<Mutex<MuxState>>,
+    key: WaiterKey,
+    id: u64,
+}
+
+impl Drop for MuxWaiter {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        if state.waiters.get(&self.key).map(|x| x.0) == Some(self.id) {
+            state.waiters.remove(&self.key);
+        }
+    }
+}
+
+impl UdpMux {
+    pub fn new(options: UdpOptions) -> Self {
+        UdpMux {
+            options,
+            state: Arc::new(Mutex::new(MuxState::default())),
+        }
+    }
+
+    /// Returns the connector creating transports through `mux`.
+    pub fn connector(mux: &Arc<UdpMux>) -> Connector {
+        let mux = Arc::clone(mux);
+        Arc::new(move |hostname, if_name| {
+            Arc::new(MuxTransport {
+                mux: Arc::clone(&mux),
+                hostname: String::from(hostname),
+                if_name: String::from(if_name),
+            })
+        })
+    }
+
+    fn seq(packet: &[u8]) -> Option<u16> {
+        packet.get(22..24).map(|x| u16::from_be_bytes([x[0], x[1]]))
+    }
+
+    fn set_seq(packet: &mut [u8], seq: u16) {
+        if let Some(x) = packet.get_mut(22..24) {
+            x.copy_from_slice(&seq.to_be_bytes());
+        }
+    }
+
+    fn exchange(
+        mux: Arc<UdpMux>,
+        hostname: String,
+        request: Vec<u8>,
+        deadline: Instant,
+    ) -> SwitchFuture<Vec<u8>> {
+        // The socket is bound in the future so that the receiving task is spawned on the runtime.
+        let sw_hostname = hostname.clone();
+        let prepare = future::lazy(move || -> Result<_, Error> {
+            let mut request = request;
+            let orig_seq =
+                UdpMux::seq(&request).ok_or_else(|| format_err!("request is too short"))?;
+            let sw_addr = resolve(&sw_hostname, mux.options.switch_port)?;
+
+            let mut state = mux.state.lock().unwrap();
+            let seq = state
+                .allocate_seq(sw_addr)
+                .ok_or_else(|| format_err!("all sequence numbers are in use"))?;
+            UdpMux::set_seq(&mut request, seq);
+            let socket = match state.socket {
+                Some(ref x) => Arc::clone(x),
+                None => {
+                    let socket = UdpSocket::bind(&SocketAddr::new(
+                        mux.options.source_address,
+                        mux.options.host_port,
+                    ))?;
+                    let socket = Arc::new(Mutex::new(socket));
+                    tokio::spawn(UdpMux::receive(
+                        Arc::clone(&socket),
+                        Arc::downgrade(&mux.state),
+                    ));
+                    state.socket = Some(Arc::clone(&socket));
+                    socket
+                }
+            };
+
+            let (tx, rx) = oneshot::channel();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.waiters.insert((sw_addr, seq), (id, tx));
+            let waiter = MuxWaiter {
+                state: Arc::clone(&mux.state),
+                key: (sw_addr, seq),
+                id,
+            };
+
+            Ok((socket, sw_addr, request, rx, waiter, orig_seq))
+        });
+
+        let exchange = prepare.and_then(|(socket, sw_addr, request, rx, waiter, orig_seq)| {
+            future::poll_fn(move || socket.lock().unwrap().poll_send_to(&request, &sw_addr))
+                .map_err(Error::from)
+                .and_then(|_| rx.map_err(|_| format_err!("receiving socket is closed")))
+                .map(move |mut reply| {
+                    UdpMux::set_seq(&mut reply, orig_seq);
+                    reply
+                })
+                .then(move |x| {
+                    drop(waiter);
+                    x
+                })
+        });
+
+        Box::new(Timeout::new_at(exchange, deadline).map_err(move |x| timeout_error(&hostname, x)))
+    }
+
+    /// Dispatches replies until the mux is dropped. Packets not waited from their source are dropped.
+    ///
+    /// A receiving error closes the socket and fails the waiting exchanges, and the next exchange binds it again.
+    fn receive(
+        socket: Arc<Mutex<UdpSocket>>,
+        state: Weak<Mutex<MuxState>>,
+    ) -> impl Future<Item = (), Error = ()> {
+        let mut buf = vec![0; 1308];
+        let recv_socket = Arc::clone(&socket);
+        let recv_state = state.clone();
+        future::poll_fn(move || loop {
+            let (len, src) = try_ready!(recv_socket.lock().unwrap().poll_recv_from(&mut buf));
+            let state = match recv_state.upgrade() {
+                Some(x) => x,
+                None => return Ok(Async::Ready(())),
+            };
+            let mut state = state.lock().unwrap();
+            if let Some(seq) = UdpMux::seq(&buf[..len]) {
+                if let Some((_, tx)) = state.waiters.remove(&(src, seq)) {
+                    let _ = tx.send(buf[..len].to_vec());
+                }
+            }
+        })
+        .map_err(move |x: std::io::Error| {
+            eprintln!("Fail to receive NSDP reply: {}", x);
+            if let Some(state) = state.upgrade() {
+                let mut state = state.lock().unwrap();
+                if state
+                    .socket
+                    .as_ref()
+                    .is_some_and(|x| Arc::ptr_eq(x, &socket))
+                {
+                    state.socket = None;
+                    state.waiters.clear();
+                }
+            }
+        })
+    }
+}
+
+/// Transport to a switch through [`UdpMux`].
+struct MuxTransport {
+    mux: Arc<UdpMux>,
+    hostname: String,
+    if_name: String,
+}
+
+impl Transport for MuxTransport {
+    fn host_mac(&self) -> Result<[u8; 6], Error> {
+        iface_mac(&self.if_name)
+    }
+
+    fn exchange(&self, request: Vec<u8>, deadline: Instant) -> SwitchFuture<Vec<u8>> {
+        UdpMux::exchange(
+            Arc::clone(&self.mux),
+            self.hostname.clone(),
+            request,
+            deadline,
+        )
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// MockTransport
+// ---------------------------------------------------------------------------------------------------------------------
+
+type MockHandler = dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync;
+
+/// In-memory transport replying by a handler.
+///
+/// The handler receives each request and returns the reply.
+/// If it returns `None`, the exchange times out at the deadline.
+pub struct MockTransport {
+    handler: Box<MockHandler>,
+    requests: Mutex<Vec<Vec<u8>>>,
+}
+
+impl MockTransport {
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        MockTransport {
+            handler: Box::new(handler),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the requests received so far.
+    pub fn requests(&self) -> Vec<Vec<u8>> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Transport for MockTransport {
+    fn host_mac(&self) -> Result<[u8; 6], Error> {
+        Ok([0; 6])
+    }
+
+    fn exchange(&self, request: Vec<u8>, deadline: Instant) -> SwitchFuture<Vec<u8>> {
+        let reply = (self.handler)(&request);
+        self.requests.lock().unwrap().push(request);
+        match reply {
+            Some(x) => Box::new(future::ok(x)),
+            None => Box::new(
+                Delay::new(deadline)
+                    .map_err(|x| format_err!("timer error: {}", x))
+                    .and_then(|_| Err(timed_out("mock"))),
+            ),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// ReplayTransport
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Transport replaying recorded pairs of request and reply.
+///
+/// Requests are matched by the queried TLVs, so that sequence numbers and MAC addresses may differ from the record.
+/// If a request was recorded several times, the replies are returned in the recorded order, and the last one is
+/// repeated. Requests which were not recorded time out at the deadline.
+pub struct ReplayTransport {
+    records: Mutex<HashMap<Vec<u8>, ReplayRecord>>,
+}
+
+/// Index of the next reply, and the recorded replies.
+type ReplayRecord = (usize, Vec<Vec<u8>>);
+
+impl ReplayTransport {
+    pub fn new<I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>>(records: I) -> Self {
+        let mut map: HashMap<Vec<u8>, ReplayRecord> = HashMap::new();
+        for (request, reply) in records {
+            map.entry(ReplayTransport::key(&request))
+                .or_default()
+                .1
+                .push(reply);
+        }
+        ReplayTransport {
+            records: Mutex::new(map),
+        }
+    }
+
+    fn key(request: &[u8]) -> Vec<u8> {
+        request.get(32..).unwrap_or_default().to_vec()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn host_mac(&self) -> Result<[u8; 6], Error> {
+        Ok([0; 6])
+    }
+
+    fn exchange(&self, request: Vec<u8>, deadline: Instant) -> SwitchFuture<Vec<u8>> {
+        let mut records = self.records.lock().unwrap();
+        let reply = records
+            .get_mut(&ReplayTransport::key(&request))
+            .and_then(|(next, replies)| {
+                let reply = replies.get(*next).or_else(|| replies.last()).cloned();
+                *next += 1;
+                reply
+            });
+        match reply {
+            Some(x) => Box::new(future::ok(x)),
+            None => Box::new(
+                Delay::new(deadline)
+                    .map_err(|x| format_err!("timer error: {}", x))
+                    .and_then(|_| Err(timed_out("replay"))),
+            ),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prosafe_switch::{PortCount, ProSafeSwitch};
+    use hex_literal::*;
+    use std::time::Duration;
+
+    const PORT_COUNT: [u8; 41] =
+        hex!("01020000000000000cc47a3a39a808bd436a1596000000804e534450000000006000000108ffff0000");
+
+    #[test]
+    fn test_mock_reply() {
+        let transport = Arc::new(MockTransport::new(|_| Some(PORT_COUNT.to_vec())));
+        let sw = ProSafeSwitch::with_transport("mock", transport.clone());
+        assert_eq!(sw.port_count().unwrap(), PortCount { count: 8 });
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0][32..], hex!("60000000ffff0000")[..]);
+    }
+
+    #[test]
+    fn test_mock_timeout() {
+        let transport = Arc::new(MockTransport::new(|_| None));
+        let mut sw = ProSafeSwitch::with_transport("mock", transport);
+        sw.set_timeout(Duration::from_millis(10));
+        let err = sw.port_count().unwrap_err();
+        assert!(format!("{}", err).starts_with("timed out"));
+    }
+
+    #[test]
+    fn test_mock_retry() {
+        let count = Mutex::new(0);
+        let transport = MockTransport::new(move |_| {
+            let mut count = count.lock().unwrap();
+            *count += 1;
+            if *count > 2 {
+                Some(PORT_COUNT.to_vec())
+            } else {
+                None
+            }
+        });
+        let mut sw = ProSafeSwitch::with_transport("mock", Arc::new(transport));
+        sw.set_timeout(Duration::from_millis(10));
+        assert!(sw.port_count().is_err());
+        sw.set_retries(1);
+        assert_eq!(sw.port_count().unwrap(), PortCount { count: 8 });
+    }
+
+    #[test]
+    fn test_mock_malformed() {
+        let transport = Arc::new(MockTransport::new(|_| Some(PORT_COUNT[..20].to_vec())));
+        let sw = ProSafeSwitch::with_transport("mock", transport);
+        assert!(sw.port_count().is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut request = vec![0; 32];
+        request.extend_from_slice(&hex!("60000000ffff0000"));
+        let mut reply = PORT_COUNT.to_vec();
+        reply[36] = 24;
+
+        let transport = ReplayTransport::new(vec![
+            (request.clone(), PORT_COUNT.to_vec()),
+            (request, reply),
+        ]);
+        let mut sw = ProSafeSwitch::with_transport("replay", Arc::new(transport));
+        sw.set_timeout(Duration::from_millis(10));
+
+        assert_eq!(sw.port_count().unwrap(), PortCount { count: 8 });
+        assert_eq!(sw.port_count().unwrap(), PortCount { count: 24 });
+        assert_eq!(sw.port_count().unwrap(), PortCount { count: 24 });
+        assert!(sw.port_stat().is_err());
+    }
+
+    #[test]
+    fn test_mux() {
+        // Virtual switches echoing the request with their number. The first one replies later, and a stranger sends
+        // the same packet before it.
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let sockets = vec![
+            socket,
+            std::net::UdpSocket::bind(("127.0.0.2", port)).unwrap(),
+        ];
+        for (i, socket) in sockets.into_iter().enumerate() {
+            std::thread::spawn(move || {
+                let mut buf = [0; 64];
+                let (len, src) = socket.recv_from(&mut buf).unwrap();
+                if i == 0 {
+                    let mut forged = buf[..len].to_vec();
+                    forged.push(9);
+                    let stranger = std::net::UdpSocket::bind("127.0.0.3:0").unwrap();
+                    stranger.send_to(&forged, src).unwrap();
+                }
+                std::thread::sleep(Duration::from_millis(100 * (1 - i as u64)));
+                let mut reply = buf[..len].to_vec();
+                reply.push(i as u8);
+                socket.send_to(&reply, src).unwrap();
+            });
+        }
+
+        let mux = Arc::new(UdpMux::new(UdpOptions {
+            source_address: IpAddr::from([127, 0, 0, 1]),
+            host_port: 0,
+            switch_port: port,
+        }));
+        let connector = UdpMux::connector(&mux);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        // The requests have the same sequence number.
+        let exchanges: Vec<_> = ["127.0.0.1", "127.0.0.2"]
+            .iter()
+            .map(|host| {
+                let mut request = vec![0; 32];
+                request[23] = 7;
+                connector(host, "lo").exchange(request, deadline)
+            })
+            .collect();
+
+        let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let replies = rt.block_on(future::join_all(exchanges)).unwrap();
+        assert_eq!((replies[0][23], replies[0][32]), (7, 0));
+        assert_eq!((replies[1][23], replies[1][32]), (7, 1));
+        assert!(mux.state.lock().unwrap().waiters.is_empty());
+
+        let mut request = vec![0; 32];
+        request[23] = 2;
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let err = rt
+            .block_on(connector("127.0.0.1", "lo").exchange(request, deadline))
+            .unwrap_err();
+        assert!(format!("{}", err).starts_with("timed out"));
+        assert!(mux.state.lock().unwrap().waiters.is_empty());
+    }
+}
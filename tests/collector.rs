@@ -0,0 +1,146 @@
+use prometheus::{Encoder, Registry, TextEncoder};
+use prosafe_exporter::collector::{Collector, ProSafeCollector};
+use prosafe_exporter::simulator::{Simulator, SimulatorConfig, VirtualSwitch};
+use prosafe_exporter::{MockTransport, ProSafeSwitch, UdpOptions, UdpTransport};
+use std::net::{IpAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Helper
+// ---------------------------------------------------------------------------------------------------------------------
+
+static SWITCH: &str = r#"
+[[switch]]
+model    = "GS105Ev2"
+name     = "sw1"
+mac      = "02:00:00:00:00:01"
+firmware = "1.6.0.4"
+ports    = 5
+
+[[switch.port]]
+no       = 1
+link     = "1G"
+rx_bytes = 1000
+tx_bytes = 2000
+errors   = 3
+"#;
+
+fn switch(hostname: &str) -> ProSafeSwitch {
+    let config: SimulatorConfig = toml::from_str(SWITCH).unwrap();
+    let sw = VirtualSwitch::new(config.switches[0].clone()).unwrap();
+    let transport = MockTransport::new(move |x| sw.answer(x, Duration::from_secs(0)).ok());
+    ProSafeSwitch::with_transport(hostname, Arc::new(transport))
+}
+
+fn gather(registry: &Registry) -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&registry.gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_collector() {
+    let registry = Registry::new();
+    registry
+        .register(Box::new(ProSafeCollector::new(switch("sw1"))))
+        .unwrap();
+
+    let body = gather(&registry);
+    assert!(body.contains("# TYPE prosafe_receive_bytes_total counter\nprosafe_receive_bytes_total{port=\"1\"} 1000\n"));
+    assert!(body.contains("# TYPE prosafe_link_speed gauge\nprosafe_link_speed{port=\"1\"} 1000\n"));
+    assert!(body.contains("prosafe_info{firmware=\"1.6.0.4\",mac=\"02:00:00:00:00:01\",model=\"GS105Ev2\",name=\"sw1\"} 1\n"));
+    assert!(body.contains("prosafe_port_count 5\n"));
+    assert!(body.contains("prosafe_up 1\n"));
+    assert!(!body.contains("prosafe_build_info"));
+}
+
+#[test]
+fn test_collector_switches() {
+    let mut unreachable =
+        ProSafeSwitch::with_transport("sw2", Arc::new(MockTransport::new(|_| None)));
+    unreachable.set_timeout(Duration::from_millis(10));
+
+    let collector = ProSafeCollector::with_switches(vec![
+        (
+            switch("sw1"),
+            vec![(String::from("switch"), String::from("sw1"))],
+        ),
+        (
+            unreachable,
+            vec![(String::from("switch"), String::from("sw2"))],
+        ),
+    ])
+    .unwrap()
+    .collectors(&[Collector::PortStat]);
+    let registry = Registry::new();
+    registry.register(Box::new(collector)).unwrap();
+
+    let body = gather(&registry);
+    assert!(body.contains("prosafe_receive_bytes_total{port=\"1\",switch=\"sw1\"} 1000\n"));
+    assert!(body.contains("prosafe_up{switch=\"sw1\"} 1\n"));
+    assert!(body.contains("prosafe_up{switch=\"sw2\"} 0\n"));
+    assert!(!body.contains("prosafe_link_speed"));
+    assert!(!body.contains("prosafe_info"));
+}
+
+#[test]
+fn test_collector_udp() {
+    let free_port = || {
+        UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    };
+    let switch_port = free_port();
+    let sim = SWITCH.replace(
+        "[[switch]]",
+        &format!(
+            "[[switch]]\nlisten = \"127.0.0.1:{}\"\ncount = 2",
+            switch_port
+        ),
+    );
+    let sim: SimulatorConfig = toml::from_str(&sim).unwrap();
+    let sim = Simulator::serve(&sim, false).unwrap();
+    thread::spawn(move || tokio::run(sim));
+
+    // Both switches bind the same port of the host.
+    let options = UdpOptions {
+        source_address: IpAddr::from([127, 0, 0, 1]),
+        host_port: free_port(),
+        switch_port,
+    };
+    let switches = ["127.0.0.1", "127.0.0.2"]
+        .iter()
+        .map(|host| {
+            let transport = UdpTransport::with_options(host, "lo", options);
+            let sw = ProSafeSwitch::with_transport(host, Arc::new(transport));
+            (sw, vec![(String::from("switch"), String::from(*host))])
+        })
+        .collect();
+    let registry = Registry::new();
+    registry
+        .register(Box::new(ProSafeCollector::with_switches(switches).unwrap()))
+        .unwrap();
+
+    let body = gather(&registry);
+    assert!(body.contains("prosafe_up{switch=\"127.0.0.1\"} 1\n"));
+    assert!(body.contains("prosafe_up{switch=\"127.0.0.2\"} 1\n"));
+    assert!(body.contains("name=\"sw1-2\""));
+}
+
+#[test]
+fn test_collector_reserved_label() {
+    for name in &["port", "collector", "model", "1switch", "__switch", ""] {
+        let labels = vec![(String::from(*name), String::from("sw1"))];
+        assert!(ProSafeCollector::with_switches(vec![(switch("sw1"), labels)]).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use prosafe_exporter::{Capabilities, Duplex, Link, MockTransport, ProSafeSwitch};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Corpus entry
+// ---------------------------------------------------------------------------------------------------------------------
+
+/// Models listed in "Tested Switches" of README, each of which has `tests/corpus/synthetic/<model>.toml`.
+///
+/// The synthetic entries are captured from `prosafe-sim`, so they check the decoders against the profiles of the
+/// models but not against real switches.
+static TESTED_MODELS: &[&str] = &[
+    "XS708E",
+    "GS308E",
+    "GS116Ev2",
+    "GS108Ev3",
+    "GS105Ev2",
+    "GS108PEv3",
+    "JGS524PE",
+];
+
+// Each file of `tests/corpus` holds the replies captured from a real switch and the expected decoded values, and each
+// file of `tests/corpus/synthetic` holds the replies of a virtual switch.
+// New entries can be captured by `cargo run --example capture -- <hostname> <if_name>`.
+
+#[derive(Deserialize)]
+struct Entry {
+    model: String,
+    source: Option<String>,
+    info: Option<Info>,
+    port_stat: Option<PortStat>,
+    speed_stat: Option<SpeedStat>,
+}
+
+#[derive(Deserialize)]
+struct Info {
+    reply: String,
+    model: String,
+    name: String,
+    mac: String,
+    firmware: String,
+}
+
+#[derive(Deserialize)]
+struct PortStat {
+    reply: String,
+    expected: Vec<PortStatExpected>,
+}
+
+#[derive(Deserialize)]
+struct PortStatExpected {
+    port_no: u8,
+    recv_bytes: u64,
+    send_bytes: u64,
+    error_pkts: u64,
+}
+
+#[derive(Deserialize)]
+struct SpeedStat {
+    reply: String,
+    expected: Vec<SpeedStatExpected>,
+}
+
+#[derive(Deserialize)]
+struct SpeedStatExpected {
+    port_no: u8,
+    link: String,
+    // Captures before the duplex was decoded don't have it.
+    duplex: Option<String>,
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Helper
+// ---------------------------------------------------------------------------------------------------------------------
+
+fn unhex(x: &str) -> Vec<u8> {
+    let x: String = x.split_whitespace().collect();
+    (0..x.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&x[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn switch(reply: &str) -> ProSafeSwitch {
+    let reply = unhex(reply);
+    let transport = MockTransport::new(move |_| Some(reply.clone()));
+    ProSafeSwitch::with_transport("corpus", Arc::new(transport))
+}
+
+fn link(x: &Link) -> &'static str {
+    match x {
+        Link::None => "None",
+        Link::Speed10Mbps => "Speed10Mbps",
+        Link::Speed100Mbps => "Speed100Mbps",
+        Link::Speed1Gbps => "Speed1Gbps",
+        Link::Speed10Gbps => "Speed10Gbps",
+        _ => "Unknown",
+    }
+}
+
+fn duplex(x: &Duplex) -> &'static str {
+    match x {
+        Duplex::None => "down",
+        Duplex::Half => "half",
+        Duplex::Full => "full",
+        _ => "unknown",
+    }
+}
+
+fn check(path: &Path) {
+    let entry: Entry = toml::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    let name = format!("{} ({})", path.display(), entry.model);
+
+    if let Some(info) = entry.info {
+        let actual = switch(&info.reply).info().unwrap();
+        assert_eq!(actual.model, info.model, "{}", name);
+        assert_eq!(actual.model, entry.model, "{}", name);
+        assert_eq!(actual.name, info.name, "{}", name);
+        assert_eq!(actual.mac, info.mac, "{}", name);
+        assert_eq!(actual.firmware, info.firmware, "{}", name);
+    }
+
+    // The records cover every port of the model.
+    let ports = Capabilities::from_profile(&entry.model)
+        .and_then(|x| x.port_count())
+        .map(|x| usize::from(x.count));
+
+    if let Some(port_stat) = entry.port_stat {
+        let actual = switch(&port_stat.reply).port_stat().unwrap();
+        assert_eq!(actual.stats.len(), port_stat.expected.len(), "{}", name);
+        if let Some(ports) = ports {
+            assert_eq!(actual.stats.len(), ports, "{}", name);
+        }
+        for (x, y) in actual.stats.iter().zip(port_stat.expected.iter()) {
+            assert_eq!(x.port_no, y.port_no, "{}", name);
+            assert_eq!(x.recv_bytes, y.recv_bytes, "{} port{}", name, y.port_no);
+            assert_eq!(x.send_bytes, y.send_bytes, "{} port{}", name, y.port_no);
+            assert_eq!(x.error_pkts, y.error_pkts, "{} port{}", name, y.port_no);
+        }
+    }
+
+    if let Some(speed_stat) = entry.speed_stat {
+        let actual = switch(&speed_stat.reply).speed_stat().unwrap();
+        assert_eq!(actual.stats.len(), speed_stat.expected.len(), "{}", name);
+        if let Some(ports) = ports {
+            assert_eq!(actual.stats.len(), ports, "{}", name);
+        }
+        for (x, y) in actual.stats.iter().zip(speed_stat.expected.iter()) {
+            assert_eq!(x.port_no, y.port_no, "{}", name);
+            assert_eq!(link(&x.link), y.link, "{} port{}", name, y.port_no);
+            if let Some(ref expected) = y.duplex {
+                assert_eq!(duplex(&x.duplex), expected, "{} port{}", name, y.port_no);
+            }
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_corpus() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    for dir in &[dir.clone(), dir.join("synthetic")] {
+        let mut count = 0;
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|x| x == "toml") {
+                check(&path);
+                count += 1;
+            }
+        }
+        assert!(count > 0, "{}", dir.display());
+    }
+}
+
+#[test]
+fn test_corpus_synthetic_models() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus/synthetic");
+    for model in TESTED_MODELS {
+        let path = dir.join(format!("{}.toml", model));
+        let entry: Entry = toml::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(&entry.model, model);
+        assert_eq!(entry.source.as_deref(), Some("prosafe-sim"), "{}", model);
+        assert!(entry.info.is_some(), "{}", model);
+        assert!(entry.port_stat.is_some(), "{}", model);
+        assert!(entry.speed_stat.is_some(), "{}", model);
+        assert!(Capabilities::from_profile(model).is_some(), "{}", model);
+    }
+}
@@ -0,0 +1,1367 @@
+use failure::Error;
+use flate2::read::GzDecoder;
+use futures::Future;
+use prosafe_exporter::config::Config;
+use prosafe_exporter::exporter::Exporter;
+use prosafe_exporter::simulator::{Simulator, SimulatorConfig, VirtualSwitch};
+use prosafe_exporter::{Connector, MockTransport, SwitchFuture, Transport};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Helper
+// ---------------------------------------------------------------------------------------------------------------------
+
+static SWITCH: &str = r#"
+[[switch]]
+model    = "GS105Ev2"
+name     = "sw1"
+mac      = "02:00:00:00:00:01"
+firmware = "1.6.0.4"
+ports    = 5
+
+[[switch.port]]
+no       = 1
+link     = "1G"
+rx_bytes = 1000
+tx_bytes = 2000
+errors   = 3
+
+[[switch.port]]
+no       = 2
+link     = "100M"
+rx_bytes = 4000
+tx_bytes = 5000
+"#;
+
+fn build_info() -> String {
+    format!(
+        "# HELP prosafe_build_info A metric with a constant '1' value labeled by version, revision and rustversion.\n\
+         # TYPE prosafe_build_info gauge\n\
+         prosafe_build_info{{revision=\"{}\",rustversion=\"{}\",version=\"{}\"}} 1\n",
+        option_env!("GIT_REVISION").unwrap_or(""),
+        option_env!("RUST_VERSION").unwrap_or(""),
+        env!("CARGO_PKG_VERSION"),
+    )
+}
+
+/// Drops the durations, which differ between runs.
+fn without_durations(body: &str) -> String {
+    body.lines()
+        .filter(|x| !x.contains("_duration_seconds"))
+        .map(|x| format!("{}\n", x))
+        .collect()
+}
+
+fn mock<F>(handler: F) -> Connector
+where
+    F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    let transport = Arc::new(MockTransport::new(handler));
+    Arc::new(move |_, _| transport.clone())
+}
+
+fn simulator(config: &str) -> Connector {
+    let config: SimulatorConfig = toml::from_str(config).unwrap();
+    let sw = VirtualSwitch::new(config.switches[0].clone()).unwrap();
+    mock(move |x| sw.answer(x, Duration::from_secs(0)).ok())
+}
+
+fn start(connector: Connector, target: Option<&str>) -> SocketAddr {
+    serve(Exporter::new("127.0.0.1:0").connector(connector), target)
+}
+
+fn serve(exporter: Exporter, target: Option<&str>) -> SocketAddr {
+    let exporter = match target {
+        Some(x) => exporter.target(Some(String::from(x))),
+        None => exporter,
+    };
+    let (addr, server) = exporter.timeout(Duration::from_millis(50)).bind().unwrap();
+    thread::spawn(move || tokio::run(server));
+    addr
+}
+
+fn config(text: &str) -> Config {
+    let config: Config = toml::from_str(text).unwrap();
+    config.validate().unwrap();
+    config
+}
+
+fn get(addr: SocketAddr, path: &str) -> (String, String) {
+    get_with_header(addr, path, "")
+}
+
+fn get_with_header(addr: SocketAddr, path: &str, header: &str) -> (String, String) {
+    let (header, body) = get_bytes(addr, path, header);
+    (header, String::from_utf8(body).unwrap())
+}
+
+fn get_bytes(addr: SocketAddr, path: &str, header: &str) -> (String, Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\n{}\r\n",
+        path, addr, header
+    )
+    .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let pos = response.windows(4).position(|x| x == b"\r\n\r\n").unwrap();
+    let header = String::from_utf8(response[..pos].to_vec()).unwrap();
+    (header, response[pos + 4..].to_vec())
+}
+
+// ---------------------------------------------------------------------------------------------------------------------
+// Test
+// ---------------------------------------------------------------------------------------------------------------------
+
+#[test]
+fn test_probe() {
+    let addr = start(simulator(SWITCH), None);
+    let (header, body) = get(addr, "/probe?target=sw1:eth0");
+
+    assert!(header.contains("content-type: text/plain; version=0.0.4"));
+    let expected = build_info()
+        + r#"# HELP prosafe_collector_success Whether the query of the collector was successful.
+# TYPE prosafe_collector_success gauge
+prosafe_collector_success{collector="info"} 1
+prosafe_collector_success{collector="port_count"} 1
+prosafe_collector_success{collector="port_stat"} 1
+prosafe_collector_success{collector="speed_stat"} 1
+# HELP prosafe_error_packets_total Transfer error in packets.
+# TYPE prosafe_error_packets_total counter
+prosafe_error_packets_total{port="1"} 3
+prosafe_error_packets_total{port="2"} 0
+prosafe_error_packets_total{port="3"} 0
+prosafe_error_packets_total{port="4"} 0
+prosafe_error_packets_total{port="5"} 0
+# HELP prosafe_info A metric with a constant '1' value labeled by model, name, mac and firmware.
+# TYPE prosafe_info gauge
+prosafe_info{firmware="1.6.0.4",mac="02:00:00:00:00:01",model="GS105Ev2",name="sw1"} 1
+# HELP prosafe_link_speed Link speed in Mbps.
+# TYPE prosafe_link_speed gauge
+prosafe_link_speed{port="1"} 1000
+prosafe_link_speed{port="2"} 100
+prosafe_link_speed{port="3"} 0
+prosafe_link_speed{port="4"} 0
+prosafe_link_speed{port="5"} 0
+# HELP prosafe_port_count Number of ports.
+# TYPE prosafe_port_count gauge
+prosafe_port_count 5
+# HELP prosafe_receive_bytes_total Incoming transfer in bytes.
+# TYPE prosafe_receive_bytes_total counter
+prosafe_receive_bytes_total{port="1"} 1000
+prosafe_receive_bytes_total{port="2"} 4000
+prosafe_receive_bytes_total{port="3"} 0
+prosafe_receive_bytes_total{port="4"} 0
+prosafe_receive_bytes_total{port="5"} 0
+# HELP prosafe_transmit_bytes_total Outgoing transfer in bytes.
+# TYPE prosafe_transmit_bytes_total counter
+prosafe_transmit_bytes_total{port="1"} 2000
+prosafe_transmit_bytes_total{port="2"} 5000
+prosafe_transmit_bytes_total{port="3"} 0
+prosafe_transmit_bytes_total{port="4"} 0
+prosafe_transmit_bytes_total{port="5"} 0
+# HELP prosafe_up The last query is successful.
+# TYPE prosafe_up gauge
+prosafe_up 1
+"#;
+    assert_eq!(without_durations(&body), expected);
+}
+
+#[test]
+fn test_metrics_static_target() {
+    let addr = start(simulator(SWITCH), Some("sw1:eth0"));
+    let (_, body) = get(addr, "/metrics");
+
+    let expected = build_info()
+        + r#"# HELP prosafe_collector_success Whether the query of the collector was successful.
+# TYPE prosafe_collector_success gauge
+prosafe_collector_success{collector="info",instance="sw1:eth0",target="sw1:eth0"} 1
+prosafe_collector_success{collector="port_count",instance="sw1:eth0",target="sw1:eth0"} 1
+prosafe_collector_success{collector="port_stat",instance="sw1:eth0",target="sw1:eth0"} 1
+prosafe_collector_success{collector="speed_stat",instance="sw1:eth0",target="sw1:eth0"} 1
+# HELP prosafe_error_packets_total Transfer error in packets.
+# TYPE prosafe_error_packets_total counter
+prosafe_error_packets_total{instance="sw1:eth0",port="1",target="sw1:eth0"} 3
+prosafe_error_packets_total{instance="sw1:eth0",port="2",target="sw1:eth0"} 0
+prosafe_error_packets_total{instance="sw1:eth0",port="3",target="sw1:eth0"} 0
+prosafe_error_packets_total{instance="sw1:eth0",port="4",target="sw1:eth0"} 0
+prosafe_error_packets_total{instance="sw1:eth0",port="5",target="sw1:eth0"} 0
+# HELP prosafe_info A metric with a constant '1' value labeled by model, name, mac and firmware.
+# TYPE prosafe_info gauge
+prosafe_info{firmware="1.6.0.4",instance="sw1:eth0",mac="02:00:00:00:00:01",model="GS105Ev2",name="sw1",target="sw1:eth0"} 1
+# HELP prosafe_link_speed Link speed in Mbps.
+# TYPE prosafe_link_speed gauge
+prosafe_link_speed{instance="sw1:eth0",port="1",target="sw1:eth0"} 1000
+prosafe_link_speed{instance="sw1:eth0",port="2",target="sw1:eth0"} 100
+prosafe_link_speed{instance="sw1:eth0",port="3",target="sw1:eth0"} 0
+prosafe_link_speed{instance="sw1:eth0",port="4",target="sw1:eth0"} 0
+prosafe_link_speed{instance="sw1:eth0",port="5",target="sw1:eth0"} 0
+# HELP prosafe_port_count Number of ports.
+# TYPE prosafe_port_count gauge
+prosafe_port_count{instance="sw1:eth0",target="sw1:eth0"} 5
+# HELP prosafe_receive_bytes_total Incoming transfer in bytes.
+# TYPE prosafe_receive_bytes_total counter
+prosafe_receive_bytes_total{instance="sw1:eth0",port="1",target="sw1:eth0"} 1000
+prosafe_receive_bytes_total{instance="sw1:eth0",port="2",target="sw1:eth0"} 4000
+prosafe_receive_bytes_total{instance="sw1:eth0",port="3",target="sw1:eth0"} 0
+prosafe_receive_bytes_total{instance="sw1:eth0",port="4",target="sw1:eth0"} 0
+prosafe_receive_bytes_total{instance="sw1:eth0",port="5",target="sw1:eth0"} 0
+# HELP prosafe_transmit_bytes_total Outgoing transfer in bytes.
+# TYPE prosafe_transmit_bytes_total counter
+prosafe_transmit_bytes_total{instance="sw1:eth0",port="1",target="sw1:eth0"} 2000
+prosafe_transmit_bytes_total{instance="sw1:eth0",port="2",target="sw1:eth0"} 5000
+prosafe_transmit_bytes_total{instance="sw1:eth0",port="3",target="sw1:eth0"} 0
+prosafe_transmit_bytes_total{instance="sw1:eth0",port="4",target="sw1:eth0"} 0
+prosafe_transmit_bytes_total{instance="sw1:eth0",port="5",target="sw1:eth0"} 0
+# HELP prosafe_up The last query is successful.
+# TYPE prosafe_up gauge
+prosafe_up{instance="sw1:eth0",target="sw1:eth0"} 1
+"#;
+    assert_eq!(without_durations(&body), expected);
+}
+
+static OPENMETRICS: &str =
+    "Accept: application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5\r\n";
+
+#[test]
+fn test_probe_openmetrics() {
+    let addr = start(simulator(SWITCH), None);
+    let (header, body) = get_with_header(addr, "/probe?target=sw1:eth0", OPENMETRICS);
+
+    assert!(header.contains("content-type: application/openmetrics-text; version=1.0.0"));
+    assert!(header.contains("vary: Accept, Accept-Encoding\r\n"));
+    assert!(body.contains(
+        "# TYPE prosafe_receive_bytes counter\n\
+         # UNIT prosafe_receive_bytes bytes\n\
+         # HELP prosafe_receive_bytes Incoming transfer in bytes.\n\
+         prosafe_receive_bytes_total{port=\"1\"} 1000\n"
+    ));
+    assert!(body.contains("# TYPE prosafe_up gauge\n"));
+    assert!(body.contains("# UNIT prosafe_probe_duration_seconds seconds\n"));
+    assert!(!body.contains("_created"));
+    assert!(body.ends_with("prosafe_up 1\n# EOF\n"));
+
+    // The text format is preferred.
+    let accept = "Accept: application/openmetrics-text;q=0.5,text/plain\r\n";
+    let (header, body) = get_with_header(addr, "/probe?target=sw1:eth0", accept);
+    assert!(header.contains("content-type: text/plain; version=0.0.4"));
+    assert!(header.contains("vary: Accept, Accept-Encoding\r\n"));
+    assert!(!body.contains("# EOF"));
+
+    let (header, _) = get_with_header(addr, "/exporter/metrics", OPENMETRICS);
+    assert!(header.contains("content-type: application/openmetrics-text; version=1.0.0"));
+}
+
+#[test]
+fn test_probe_openmetrics_created() {
+    let rebooted = Arc::new(AtomicBool::new(false));
+    let sim: SimulatorConfig = toml::from_str(SWITCH).unwrap();
+    let before = VirtualSwitch::new(sim.switches[0].clone()).unwrap();
+    let sim: SimulatorConfig =
+        toml::from_str(&SWITCH.replace("rx_bytes = 1000", "rx_bytes = 10")).unwrap();
+    let after = VirtualSwitch::new(sim.switches[0].clone()).unwrap();
+    let connector = {
+        let rebooted = Arc::clone(&rebooted);
+        mock(move |x| {
+            let sw = if rebooted.load(Ordering::SeqCst) {
+                &after
+            } else {
+                &before
+            };
+            sw.answer(x, Duration::from_secs(0)).ok()
+        })
+    };
+    let addr = start(connector, None);
+
+    let (_, body) = get_with_header(addr, "/probe?target=sw1:eth0", OPENMETRICS);
+    assert!(body.contains("prosafe_receive_bytes_total{port=\"1\"} 1000\n"));
+    assert!(!body.contains("_created"));
+
+    // The counter of port 1 decreased, and the others didn't.
+    rebooted.store(true, Ordering::SeqCst);
+    let (_, body) = get_with_header(addr, "/probe?target=sw1:eth0", OPENMETRICS);
+    assert!(body.contains("prosafe_receive_bytes_total{port=\"1\"} 10\n"));
+    assert!(body.contains("prosafe_receive_bytes_created{port=\"1\"} "));
+    assert!(!body.contains("prosafe_receive_bytes_created{port=\"2\"}"));
+    assert!(!body.contains("prosafe_transmit_bytes_created"));
+
+    // Another target doesn't share the counters.
+    let (_, body) = get_with_header(addr, "/probe?target=sw2:eth0", OPENMETRICS);
+    assert!(!body.contains("_created"));
+}
+
+#[test]
+fn test_probe_gzip() {
+    let addr = start(simulator(SWITCH), None);
+    let (header, body) = get_bytes(addr, "/probe?target=sw1:eth0", "Accept-Encoding: gzip\r\n");
+    assert!(header.contains("content-encoding: gzip"));
+    assert!(header.contains("vary: Accept, Accept-Encoding\r\n"));
+
+    let mut text = String::new();
+    GzDecoder::new(&body[..]).read_to_string(&mut text).unwrap();
+    assert!(text.contains("prosafe_receive_bytes_total{port=\"1\"} 1000\n"));
+
+    let (header, _) = get_bytes(
+        addr,
+        "/probe?target=sw1:eth0",
+        "Accept-Encoding: gzip;q=0\r\n",
+    );
+    assert!(!header.contains("content-encoding"));
+    assert!(header.contains("vary: Accept, Accept-Encoding\r\n"));
+}
+
+#[test]
+fn test_probe_any_iface() {
+    let addr = start(simulator(SWITCH), None);
+    let (_, body) = get(addr, "/probe?target=sw1:*");
+    assert!(body.contains("prosafe_up 1\n"));
+    assert!(body.contains("prosafe_receive_bytes_total{port=\"2\"} 4000\n"));
+}
+
+#[test]
+fn test_probe_timeout() {
+    let addr = start(mock(|_| None), None);
+    let (_, body) = get(addr, "/probe?target=sw1:eth0");
+
+    let expected = build_info()
+        + r#"# HELP prosafe_collector_success Whether the query of the collector was successful.
+# TYPE prosafe_collector_success gauge
+prosafe_collector_success{collector="info"} 0
+prosafe_collector_success{collector="port_count"} 0
+prosafe_collector_success{collector="port_stat"} 0
+prosafe_collector_success{collector="speed_stat"} 0
+# HELP prosafe_up The last query is successful.
+# TYPE prosafe_up gauge
+prosafe_up 0
+"#;
+    assert_eq!(without_durations(&body), expected);
+}
+
+#[test]
+fn test_probe_garbage() {
+    let addr = start(mock(|_| Some(b"garbage".to_vec())), Some("sw1:eth0"));
+    let (_, body) = get(addr, "/metrics");
+
+    let expected = build_info()
+        + r#"# HELP prosafe_collector_success Whether the query of the collector was successful.
+# TYPE prosafe_collector_success gauge
+prosafe_collector_success{collector="info",instance="sw1:eth0",target="sw1:eth0"} 0
+prosafe_collector_success{collector="port_count",instance="sw1:eth0",target="sw1:eth0"} 0
+prosafe_collector_success{collector="port_stat",instance="sw1:eth0",target="sw1:eth0"} 0
+prosafe_collector_success{collector="speed_stat",instance="sw1:eth0",target="sw1:eth0"} 0
+# HELP prosafe_up The last query is successful.
+# TYPE prosafe_up gauge
+prosafe_up{instance="sw1:eth0",target="sw1:eth0"} 0
+"#;
+    assert_eq!(without_durations(&body), expected);
+}
+
+#[test]
+fn test_probe_collector_failure() {
+    let config: SimulatorConfig = toml::from_str(SWITCH).unwrap();
+    let sw = VirtualSwitch::new(config.switches[0].clone()).unwrap();
+    let addr = start(
+        mock(move |x| {
+            if x.ends_with(&[0x0c, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00]) {
+                None
+            } else {
+                sw.answer(x, Duration::from_secs(0)).ok()
+            }
+        }),
+        None,
+    );
+    let (_, body) = get(addr, "/probe?target=sw1:eth0");
+    assert!(body.contains("prosafe_up 1\n"));
+    assert!(body.contains("prosafe_collector_success{collector=\"port_stat\"} 1\n"));
+    assert!(body.contains("prosafe_collector_success{collector=\"speed_stat\"} 0\n"));
+    assert!(body.contains("prosafe_collector_duration_seconds{collector=\"speed_stat\"} 0.0"));
+    assert!(body.contains("prosafe_probe_duration_seconds "));
+    assert!(!body.contains("prosafe_link_speed"));
+}
+
+fn hanging_switch(hangs: &'static [u8], offset: Duration) -> SocketAddr {
+    let sim: SimulatorConfig = toml::from_str(SWITCH).unwrap();
+    let sw = VirtualSwitch::new(sim.switches[0].clone()).unwrap();
+    let connector = mock(move |x| {
+        if x.ends_with(hangs) {
+            None
+        } else {
+            sw.answer(x, Duration::from_secs(0)).ok()
+        }
+    });
+    let config = config(
+        r#"
+        [[target]]
+        name      = "switch1"
+        host      = "sw1"
+        interface = "eth0"
+        timeout   = 2000
+        "#,
+    );
+    let exporter = Exporter::new("127.0.0.1:0")
+        .connector(connector)
+        .config(config)
+        .timeout_offset(offset);
+    serve(exporter, None)
+}
+
+#[test]
+fn test_probe_scrape_timeout() {
+    let speed_stat = &[0x0c, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00];
+    let addr = hanging_switch(speed_stat, Duration::from_millis(200));
+    let start = Instant::now();
+    let (_, body) = get_with_header(
+        addr,
+        "/probe?target=switch1",
+        "X-Prometheus-Scrape-Timeout-Seconds: 0.5\r\n",
+    );
+    assert!(start.elapsed() < Duration::from_millis(1000));
+    assert!(body.contains("prosafe_up 1\n"));
+    assert!(body.contains("prosafe_receive_bytes_total{port=\"1\"} 1000\n"));
+    assert!(body.contains("prosafe_collector_success{collector=\"port_stat\"} 1\n"));
+    assert!(body.contains("prosafe_collector_success{collector=\"speed_stat\"} 0\n"));
+}
+
+#[test]
+fn test_probe_scrape_timeout_unreachable() {
+    // All requests end with `ffff0000`, so nothing is answered.
+    let addr = hanging_switch(&[0xff, 0xff, 0x00, 0x00], Duration::from_millis(0));
+    let start = Instant::now();
+    let (_, body) = get_with_header(
+        addr,
+        "/probe?target=switch1",
+        "X-Prometheus-Scrape-Timeout-Seconds: 0.3\r\n",
+    );
+    assert!(start.elapsed() < Duration::from_millis(1000));
+    assert!(body.contains("prosafe_up 0\n"));
+    assert!(body.contains("prosafe_collector_success{collector=\"info\"} 0\n"));
+    assert!(body.contains("prosafe_collector_success{collector=\"speed_stat\"} 0\n"));
+}
+
+#[test]
+fn test_probe_unknown_link() {
+    let config = SWITCH.replace("link     = \"100M\"", "link     = \"9\"");
+    let addr = start(simulator(&config), None);
+    let (_, body) = get(addr, "/probe?target=sw1:eth0");
+    assert!(body.contains("prosafe_link_speed{port=\"1\"} 1000\n"));
+    assert!(body.contains("prosafe_link_speed{port=\"2\"} 0\n"));
+    assert!(body.contains("prosafe_up 1\n"));
+}
+
+#[test]
+fn test_probe_large_counter() {
+    // Above 2^53, which `f64` can't hold exactly.
+    let config = SWITCH.replace("rx_bytes = 1000", "rx_bytes = 9007199254740993");
+    let addr = start(simulator(&config), None);
+    let (_, body) = get(addr, "/probe?target=sw1:eth0");
+    assert!(body.contains("# TYPE prosafe_receive_bytes_total counter\n"));
+    assert!(body.contains("prosafe_receive_bytes_total{port=\"1\"} 9007199254740993\n"));
+    let (_, body) = get_with_header(addr, "/probe?target=sw1:eth0", OPENMETRICS);
+    assert!(body.contains("prosafe_receive_bytes_total{port=\"1\"} 9007199254740993\n"));
+}
+
+/// Returns the exporter address, and the transport recording the requests to the simulated switch.
+fn recorded_switch(config: &str) -> (SocketAddr, Arc<MockTransport>) {
+    let config: SimulatorConfig = toml::from_str(config).unwrap();
+    let sw = VirtualSwitch::new(config.switches[0].clone()).unwrap();
+    let transport = Arc::new(MockTransport::new(move |x| {
+        sw.answer(x, Duration::from_secs(0)).ok()
+    }));
+    let connector: Connector = {
+        let transport = Arc::clone(&transport);
+        Arc::new(move |_, _| transport.clone())
+    };
+    (start(connector, None), transport)
+}
+
+#[test]
+fn test_probe_profile() {
+    let port_count = |transport: &MockTransport| {
+        transport
+            .requests()
+            .iter()
+            .any(|x| x.ends_with(&[0x60, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00]))
+    };
+
+    // The port count of a known model is queried too.
+    let (addr, transport) = recorded_switch(SWITCH);
+    let (_, body) = get(addr, "/probe?target=sw1:eth0");
+    assert!(body.contains("prosafe_port_count 5\n"));
+    assert!(port_count(&transport));
+
+    // Fewer ports than the model are reported, and more ports are rejected.
+    let (addr, _) = recorded_switch(&SWITCH.replace("ports    = 5", "ports    = 3"));
+    let (_, body) = get(addr, "/probe?target=sw1:eth0");
+    assert!(body.contains("prosafe_port_count 3\n"));
+    let (addr, _) = recorded_switch(&SWITCH.replace("ports    = 5", "ports    = 9"));
+    let (_, body) = get(addr, "/probe?target=sw1:eth0");
+    assert!(body.contains("prosafe_collector_success{collector=\"port_count\"} 0\n"));
+    assert!(!body.contains("prosafe_port_count"));
+
+    // An unknown model is not checked.
+    let (addr, transport) = recorded_switch(
+        &SWITCH
+            .replace("GS105Ev2", "XX999")
+            .replace("ports    = 5", "ports    = 9"),
+    );
+    let (_, body) = get(addr, "/probe?target=sw1:eth0");
+    assert!(body.contains("prosafe_port_count 9\n"));
+    assert!(port_count(&transport));
+
+    // A link faster than the model supports is rejected.
+    let (addr, _) = recorded_switch(&SWITCH.replace("\"1G\"", "\"10G\""));
+    let (_, body) = get(addr, "/probe?target=sw1:eth0");
+    assert!(body.contains("prosafe_collector_success{collector=\"speed_stat\"} 0\n"));
+    assert!(!body.contains("prosafe_link_speed"));
+    let (addr, _) = recorded_switch(
+        &SWITCH
+            .replace("\"1G\"", "\"10G\"")
+            .replace("GS105Ev2", "XS708E"),
+    );
+    let (_, body) = get(addr, "/probe?target=sw1:eth0");
+    assert!(body.contains("prosafe_link_speed{port=\"1\"} 10000\n"));
+}
+
+#[test]
+fn test_probe_debug() {
+    let addr = start(simulator(SWITCH), None);
+    let (header, body) = get(addr, "/probe?target=sw1:eth0&debug=1");
+
+    assert!(header.contains("content-type: text/plain; charset=utf-8"));
+    let report: toml::Value = toml::from_str(&body).unwrap();
+    assert_eq!(report["target"].as_str(), Some("sw1:eth0"));
+    assert_eq!(report["interface"].as_str(), Some("eth0"));
+    assert_eq!(report["up"].as_bool(), Some(true));
+
+    let exchange = report["exchange"].as_array().unwrap();
+    assert_eq!(exchange.len(), 4);
+    assert!(exchange[2]["request"]
+        .as_str()
+        .unwrap()
+        .ends_with("10000000ffff0000"));
+    assert_eq!(exchange[2]["tlv"][0]["name"].as_str(), Some("PortStat"));
+    assert_eq!(exchange[2]["tlv"][0]["length"].as_integer(), Some(49));
+    assert!(exchange[2]["decoded"][0]
+        .as_str()
+        .unwrap()
+        .contains("PortStat { port_no: 1, recv_bytes: 1000, send_bytes: 2000, error_pkts: 3 }"));
+}
+
+#[test]
+fn test_probe_debug_timeout() {
+    let addr = start(mock(|_| None), None);
+    let (_, body) = get(addr, "/probe?target=sw1:eth0&debug=1");
+
+    let report: toml::Value = toml::from_str(&body).unwrap();
+    assert_eq!(report["up"].as_bool(), Some(false));
+    let exchange = report["exchange"].as_array().unwrap();
+    assert!(exchange[0]["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("timed out"));
+}
+
+#[test]
+fn test_probe_config() {
+    let config = config(
+        r#"
+        [[target]]
+        name       = "switch1"
+        host       = "sw1"
+        interface  = "eth0"
+        collectors = ["port_stat"]
+        labels     = { site = "tokyo" }
+        "#,
+    );
+    let exporter = Exporter::new("127.0.0.1:0")
+        .connector(simulator(SWITCH))
+        .config(config);
+    let addr = serve(exporter, None);
+    let (_, body) = get(addr, "/probe?target=switch1");
+
+    let expected = build_info()
+        + r#"# HELP prosafe_collector_success Whether the query of the collector was successful.
+# TYPE prosafe_collector_success gauge
+prosafe_collector_success{collector="port_stat",site="tokyo"} 1
+# HELP prosafe_error_packets_total Transfer error in packets.
+# TYPE prosafe_error_packets_total counter
+prosafe_error_packets_total{port="1",site="tokyo"} 3
+prosafe_error_packets_total{port="2",site="tokyo"} 0
+prosafe_error_packets_total{port="3",site="tokyo"} 0
+prosafe_error_packets_total{port="4",site="tokyo"} 0
+prosafe_error_packets_total{port="5",site="tokyo"} 0
+# HELP prosafe_receive_bytes_total Incoming transfer in bytes.
+# TYPE prosafe_receive_bytes_total counter
+prosafe_receive_bytes_total{port="1",site="tokyo"} 1000
+prosafe_receive_bytes_total{port="2",site="tokyo"} 4000
+prosafe_receive_bytes_total{port="3",site="tokyo"} 0
+prosafe_receive_bytes_total{port="4",site="tokyo"} 0
+prosafe_receive_bytes_total{port="5",site="tokyo"} 0
+# HELP prosafe_transmit_bytes_total Outgoing transfer in bytes.
+# TYPE prosafe_transmit_bytes_total counter
+prosafe_transmit_bytes_total{port="1",site="tokyo"} 2000
+prosafe_transmit_bytes_total{port="2",site="tokyo"} 5000
+prosafe_transmit_bytes_total{port="3",site="tokyo"} 0
+prosafe_transmit_bytes_total{port="4",site="tokyo"} 0
+prosafe_transmit_bytes_total{port="5",site="tokyo"} 0
+# HELP prosafe_up The last query is successful.
+# TYPE prosafe_up gauge
+prosafe_up{site="tokyo"} 1
+"#;
+    assert_eq!(without_durations(&body), expected);
+
+    let (_, body) = get(addr, "/probe?target=switch2");
+    assert_eq!(body, build_info());
+}
+
+#[test]
+fn test_probe_collect() {
+    let addr = start(simulator(SWITCH), None);
+    let (_, body) = get(
+        addr,
+        "/probe?target=sw1:eth0&collect[]=port_count&collect%5B%5D=speed_stat",
+    );
+
+    let expected = build_info()
+        + r#"# HELP prosafe_collector_success Whether the query of the collector was successful.
+# TYPE prosafe_collector_success gauge
+prosafe_collector_success{collector="port_count"} 1
+prosafe_collector_success{collector="speed_stat"} 1
+# HELP prosafe_link_speed Link speed in Mbps.
+# TYPE prosafe_link_speed gauge
+prosafe_link_speed{port="1"} 1000
+prosafe_link_speed{port="2"} 100
+prosafe_link_speed{port="3"} 0
+prosafe_link_speed{port="4"} 0
+prosafe_link_speed{port="5"} 0
+# HELP prosafe_port_count Number of ports.
+# TYPE prosafe_port_count gauge
+prosafe_port_count 5
+# HELP prosafe_up The last query is successful.
+# TYPE prosafe_up gauge
+prosafe_up 1
+"#;
+    assert_eq!(without_durations(&body), expected);
+
+    let (header, body) = get(addr, "/probe?target=sw1:eth0&collect[]=poe");
+    assert!(header.starts_with("HTTP/1.0 400"));
+    assert_eq!(body, "unknown collector 'poe'\n");
+}
+
+#[test]
+fn test_probe_cache_interval() {
+    let sim: SimulatorConfig = toml::from_str(SWITCH).unwrap();
+    let sw = VirtualSwitch::new(sim.switches[0].clone()).unwrap();
+    let transport = Arc::new(MockTransport::new(move |x| {
+        sw.answer(x, Duration::from_secs(0)).ok()
+    }));
+    let connector: Connector = {
+        let transport = Arc::clone(&transport);
+        Arc::new(move |_, _| transport.clone())
+    };
+    let config = config(
+        r#"
+        [[target]]
+        name           = "switch1"
+        host           = "sw1"
+        interface      = "eth0"
+        cache_interval = { info = 60000, port_count = 60000 }
+        "#,
+    );
+    let exporter = Exporter::new("127.0.0.1:0")
+        .connector(connector)
+        .config(config);
+    let addr = serve(exporter, None);
+
+    let (_, body) = get(addr, "/probe?target=switch1");
+    assert!(body.contains("prosafe_port_count 5\n"));
+    let requests = transport.requests().len();
+
+    // Only the statistics are queried, and the others are served from the cache.
+    let (_, body) = get(addr, "/probe?target=switch1");
+    assert_eq!(transport.requests().len() - requests, 2);
+    assert!(body.contains("prosafe_up 1\n"));
+    assert!(body.contains("model=\"GS105Ev2\""));
+    assert!(body.contains("prosafe_port_count 5\n"));
+    assert!(body.contains("prosafe_collector_success{collector=\"info\"} 1\n"));
+    assert!(body.contains("prosafe_receive_bytes_total{port=\"1\"} 1000\n"));
+
+    // All of them are served from the cache.
+    let requests = transport.requests().len();
+    let (_, body) = get(addr, "/probe?target=switch1&collect[]=info");
+    assert_eq!(transport.requests().len(), requests);
+    assert!(body.contains("prosafe_up 1\n"));
+    assert!(body.contains("model=\"GS105Ev2\""));
+}
+
+#[test]
+fn test_metrics_config_target() {
+    let config = config(
+        r#"
+        [[target]]
+        name       = "switch1"
+        host       = "sw1"
+        interface  = "eth0"
+        collectors = ["info"]
+        "#,
+    );
+    let exporter = Exporter::new("127.0.0.1:0")
+        .connector(simulator(SWITCH))
+        .config(config);
+    let addr = serve(exporter, Some("switch1"));
+    let (_, body) = get(addr, "/metrics");
+
+    let expected = build_info()
+        + r#"# HELP prosafe_collector_success Whether the query of the collector was successful.
+# TYPE prosafe_collector_success gauge
+prosafe_collector_success{collector="info",instance="switch1",target="switch1"} 1
+# HELP prosafe_info A metric with a constant '1' value labeled by model, name, mac and firmware.
+# TYPE prosafe_info gauge
+prosafe_info{firmware="1.6.0.4",instance="switch1",mac="02:00:00:00:00:01",model="GS105Ev2",name="sw1",target="switch1"} 1
+# HELP prosafe_up The last query is successful.
+# TYPE prosafe_up gauge
+prosafe_up{instance="switch1",target="switch1"} 1
+"#;
+    assert_eq!(without_durations(&body), expected);
+}
+
+#[test]
+fn test_metrics_multiple_targets() {
+    let sw1 = simulator(SWITCH);
+    let sw2 = mock(|_| None);
+    let connector: Connector = Arc::new(move |host, if_name| {
+        if host == "sw1" {
+            sw1(host, if_name)
+        } else {
+            sw2(host, if_name)
+        }
+    });
+    let config = config(
+        r#"
+        [[target]]
+        name       = "switch1"
+        host       = "sw1"
+        interface  = "eth0"
+        collectors = ["port_count"]
+        labels     = { site = "tokyo" }
+
+        [[target]]
+        name       = "switch2"
+        host       = "sw2"
+        interface  = "eth0"
+        timeout    = 50
+        "#,
+    );
+    let exporter = Exporter::new("127.0.0.1:0")
+        .connector(connector)
+        .config(config);
+    let addr = serve(exporter, None);
+    let (_, body) = get(addr, "/metrics");
+
+    let expected = build_info()
+        + r#"# HELP prosafe_collector_success Whether the query of the collector was successful.
+# TYPE prosafe_collector_success gauge
+prosafe_collector_success{collector="info",instance="switch2",site="",target="switch2"} 0
+prosafe_collector_success{collector="port_count",instance="switch1",site="tokyo",target="switch1"} 1
+prosafe_collector_success{collector="port_count",instance="switch2",site="",target="switch2"} 0
+prosafe_collector_success{collector="port_stat",instance="switch2",site="",target="switch2"} 0
+prosafe_collector_success{collector="speed_stat",instance="switch2",site="",target="switch2"} 0
+# HELP prosafe_port_count Number of ports.
+# TYPE prosafe_port_count gauge
+prosafe_port_count{instance="switch1",site="tokyo",target="switch1"} 5
+# HELP prosafe_up The last query is successful.
+# TYPE prosafe_up gauge
+prosafe_up{instance="switch1",site="tokyo",target="switch1"} 1
+prosafe_up{instance="switch2",site="",target="switch2"} 0
+"#;
+    assert_eq!(without_durations(&body), expected);
+}
+
+#[test]
+fn test_metrics_repeated_target() {
+    let exporter = Exporter::new("127.0.0.1:0")
+        .connector(simulator(SWITCH))
+        .targets(vec![String::from("sw1:eth0"), String::from("sw2:eth0")]);
+    let addr = serve(exporter, None);
+    let (_, body) = get(addr, "/metrics");
+    assert!(body.contains("prosafe_up{instance=\"sw1:eth0\",target=\"sw1:eth0\"} 1\n"));
+    assert!(body.contains("prosafe_up{instance=\"sw2:eth0\",target=\"sw2:eth0\"} 1\n"));
+    assert!(body.contains(
+        "prosafe_link_speed{instance=\"sw2:eth0\",port=\"2\",target=\"sw2:eth0\"} 100\n"
+    ));
+}
+
+fn wait_up(addr: SocketAddr) -> String {
+    for _ in 0..100 {
+        let (_, body) = get(addr, "/metrics");
+        if body.contains("prosafe_up{instance=\"switch1\",target=\"switch1\"} 1\n") {
+            return body;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("polling did not succeed");
+}
+
+#[test]
+fn test_metrics_polling() {
+    let sim: SimulatorConfig = toml::from_str(SWITCH).unwrap();
+    let sw = VirtualSwitch::new(sim.switches[0].clone()).unwrap();
+    let transport = Arc::new(MockTransport::new(move |x| {
+        sw.answer(x, Duration::from_secs(0)).ok()
+    }));
+    let transport0 = Arc::clone(&transport);
+    let connector: Connector = Arc::new(move |_, _| transport0.clone());
+
+    let config = config(
+        r#"
+        [[target]]
+        name      = "switch1"
+        host      = "sw1"
+        interface = "eth0"
+        interval  = 60000
+        "#,
+    );
+    let exporter = Exporter::new("127.0.0.1:0")
+        .connector(connector)
+        .config(config);
+    let addr = serve(exporter, None);
+
+    let body = wait_up(addr);
+    assert!(body.contains(
+        "prosafe_last_success_timestamp_seconds{instance=\"switch1\",target=\"switch1\"} "
+    ));
+    assert!(body.contains(
+        "prosafe_receive_bytes_total{instance=\"switch1\",port=\"1\",target=\"switch1\"} 1000\n"
+    ));
+
+    let requests = transport.requests().len();
+    let (_, body) = get(addr, "/metrics");
+    assert!(body.contains(
+        "prosafe_receive_bytes_total{instance=\"switch1\",port=\"1\",target=\"switch1\"} 1000\n"
+    ));
+    let (_, body) = get(addr, "/probe?target=switch1");
+    assert!(body.contains("prosafe_receive_bytes_total{port=\"1\"} 1000\n"));
+    assert_eq!(transport.requests().len(), requests);
+}
+
+#[test]
+fn test_metrics_polling_stale() {
+    let config = config(
+        r#"
+        [[target]]
+        name      = "switch1"
+        host      = "sw1"
+        interface = "eth0"
+        interval  = 60000
+        staleness = 200
+        "#,
+    );
+    let exporter = Exporter::new("127.0.0.1:0")
+        .connector(simulator(SWITCH))
+        .config(config);
+    let addr = serve(exporter, None);
+
+    wait_up(addr);
+    thread::sleep(Duration::from_millis(300));
+
+    let (_, body) = get(addr, "/metrics");
+    assert!(body.contains("prosafe_up{instance=\"switch1\",target=\"switch1\"} 0\n"));
+    assert!(body.contains(
+        "prosafe_last_success_timestamp_seconds{instance=\"switch1\",target=\"switch1\"} "
+    ));
+    assert!(!body.contains("prosafe_receive_bytes_total"));
+}
+
+#[test]
+fn test_unknown_static_target() {
+    let result = Exporter::new("127.0.0.1:0")
+        .target(Some(String::from("switch1")))
+        .bind();
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_zero_poll_interval() {
+    let result = Exporter::new("127.0.0.1:0")
+        .poll_interval(Some(Duration::from_secs(0)))
+        .bind();
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_probe_config_udp() {
+    let port = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let sim = SWITCH.replace(
+        "[[switch]]",
+        &format!("[[switch]]\nlisten = \"127.0.0.1:{}\"", port),
+    );
+    let sim: SimulatorConfig = toml::from_str(&sim).unwrap();
+    let sim = Simulator::serve(&sim, false).unwrap();
+    thread::spawn(move || tokio::run(sim));
+
+    // The host port is ephemeral so that the test doesn't conflict with a running exporter.
+    let config = config(&format!(
+        r#"
+        [[target]]
+        name           = "switch1"
+        host           = "127.0.0.1"
+        interface      = "lo"
+        source_address = "127.0.0.1"
+        host_port      = 0
+        switch_port    = {}
+        timeout        = 200
+        retries        = 2
+        "#,
+        port
+    ));
+    let addr = serve(Exporter::new("127.0.0.1:0").config(config), None);
+    let (_, body) = get(addr, "/probe?target=switch1");
+    assert!(body.contains("prosafe_up 1\n"));
+    assert!(body.contains("prosafe_receive_bytes_total{port=\"1\"} 1000\n"));
+}
+
+#[test]
+fn test_metrics_udp_concurrent() {
+    let port = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let sim = SWITCH.replace(
+        "[[switch]]",
+        &format!(
+            "[[switch]]\nlisten = \"127.0.0.1:{}\"\ncount = 2\nlatency = 300",
+            port
+        ),
+    );
+    let sim: SimulatorConfig = toml::from_str(&sim).unwrap();
+    let sim = Simulator::serve(&sim, false).unwrap();
+    thread::spawn(move || tokio::run(sim));
+
+    // Both targets share the socket of the host, so they are queried concurrently.
+    let config = config(&format!(
+        r#"
+        [[target]]
+        name           = "switch1"
+        host           = "127.0.0.1"
+        interface      = "lo"
+        source_address = "127.0.0.1"
+        host_port      = 0
+        switch_port    = {0}
+
+        [[target]]
+        name           = "switch2"
+        host           = "127.0.0.2"
+        interface      = "lo"
+        source_address = "127.0.0.1"
+        host_port      = 0
+        switch_port    = {0}
+        "#,
+        port
+    ));
+    let addr = serve(Exporter::new("127.0.0.1:0").config(config), None);
+    let start = Instant::now();
+    let (_, body) = get(addr, "/metrics");
+    assert!(body.contains("prosafe_up{instance=\"switch1\",target=\"switch1\"} 1\n"));
+    assert!(body.contains("prosafe_up{instance=\"switch2\",target=\"switch2\"} 1\n"));
+    assert!(body.contains("name=\"sw1-2\""));
+    assert!(start.elapsed() < Duration::from_millis(2000));
+}
+
+/// Replies after the delay without blocking the runtime.
+struct SlowTransport(Arc<MockTransport>);
+
+impl Transport for SlowTransport {
+    fn host_mac(&self) -> Result<[u8; 6], Error> {
+        self.0.host_mac()
+    }
+
+    fn exchange(&self, request: Vec<u8>, deadline: Instant) -> SwitchFuture<Vec<u8>> {
+        let reply = self.0.exchange(request, deadline);
+        let delay = Delay::new(Instant::now() + Duration::from_millis(50));
+        Box::new(delay.map_err(Error::from).and_then(|_| reply))
+    }
+}
+
+fn slow_switch(ttl: Duration) -> (Arc<MockTransport>, SocketAddr) {
+    let sim: SimulatorConfig = toml::from_str(SWITCH).unwrap();
+    let sw = VirtualSwitch::new(sim.switches[0].clone()).unwrap();
+    let transport = Arc::new(MockTransport::new(move |x| {
+        sw.answer(x, Duration::from_secs(0)).ok()
+    }));
+    let slow: Arc<dyn Transport> = Arc::new(SlowTransport(Arc::clone(&transport)));
+    let connector: Connector = Arc::new(move |_, _| slow.clone());
+
+    let config = config(
+        r#"
+        [[target]]
+        name      = "switch1"
+        host      = "sw1"
+        interface = "eth0"
+        timeout   = 1000
+
+        [[target]]
+        name      = "switch2"
+        host      = "sw1"
+        interface = "eth1"
+        timeout   = 1000
+        "#,
+    );
+    let exporter = Exporter::new("127.0.0.1:0")
+        .connector(connector)
+        .config(config)
+        .probe_ttl(ttl);
+    (transport, serve(exporter, None))
+}
+
+#[test]
+fn test_probe_coalesced() {
+    let (transport, addr) = slow_switch(Duration::from_secs(0));
+
+    let probes: Vec<_> = (0..4)
+        .map(|_| thread::spawn(move || get(addr, "/probe?target=switch1").1))
+        .collect();
+    for probe in probes {
+        assert!(probe.join().unwrap().contains("prosafe_up 1\n"));
+    }
+    let coalesced = transport.requests().len();
+
+    let (_, body) = get(addr, "/probe?target=switch2");
+    assert!(body.contains("prosafe_up 1\n"));
+    assert_eq!(transport.requests().len() - coalesced, coalesced);
+}
+
+#[test]
+fn test_probe_ttl() {
+    let (transport, addr) = slow_switch(Duration::from_secs(60));
+
+    let (_, body) = get(addr, "/probe?target=switch1");
+    assert!(body.contains("prosafe_up 1\n"));
+    let requests = transport.requests().len();
+    let (_, body) = get(addr, "/probe?target=switch1");
+    assert!(body.contains("prosafe_receive_bytes_total{port=\"1\"} 1000\n"));
+    assert_eq!(transport.requests().len(), requests);
+
+    // Another target isn't served from the result.
+    get(addr, "/probe?target=switch2");
+    assert!(transport.requests().len() > requests);
+}
+
+#[test]
+fn test_probe_without_target() {
+    let addr = start(simulator(SWITCH), None);
+    let (_, body) = get(addr, "/probe");
+    assert_eq!(body, build_info());
+}
+
+#[test]
+fn test_api_switch() {
+    let config = SWITCH.replace("rx_bytes = 1000", "rx_bytes = 1000\nrx_rate  = 1000000");
+    let config: SimulatorConfig = toml::from_str(&config).unwrap();
+    let sw = VirtualSwitch::new(config.switches[0].clone()).unwrap();
+    let start_time = Instant::now();
+    let addr = start(mock(move |x| sw.answer(x, start_time.elapsed()).ok()), None);
+    let (header, body) = get(addr, "/api/v1/switches/sw1:eth0");
+
+    assert!(header.contains("content-type: application/json"));
+    let state: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(state["target"], "sw1:eth0");
+    assert_eq!(state["up"], true);
+    assert_eq!(state["interface"], "eth0");
+    assert_eq!(state["info"]["model"], "GS105Ev2");
+    assert_eq!(state["info"]["mac"], "02:00:00:00:00:01");
+    assert_eq!(state["port_count"], 5);
+    assert_eq!(state["port_stats"]["stats"][1]["recv_bytes"], 4000);
+    assert_eq!(state["port_stats"]["stats"][0]["error_pkts"], 3);
+    assert_eq!(state["speed_stats"]["stats"][0]["link"], "1G");
+    assert_eq!(state["speed_stats"]["stats"][1]["link"], "100M");
+    assert_eq!(state["speed_stats"]["stats"][1]["duplex"], "full");
+    assert_eq!(state["error"], serde_json::Value::Null);
+    assert_eq!(state["rates"].as_array().unwrap().len(), 0);
+
+    // The rates are computed from the previous result. The simulator counts whole seconds.
+    // The target in the path is percent-decoded.
+    thread::sleep(Duration::from_millis(1100));
+    let (_, body) = get(addr, "/api/v1/switches/sw1%3Aeth0");
+    let state: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(state["target"], "sw1:eth0");
+    let rates = state["rates"].as_array().unwrap();
+    assert_eq!(rates.len(), 5);
+    assert_eq!(rates[0]["port_no"], 1);
+    let rate = rates[0]["recv_bytes"].as_f64().unwrap();
+    assert!(rate > 500000.0 && rate < 2000000.0, "{}", rate);
+    assert_eq!(rates[1]["send_bytes"], 0.0);
+
+    let (header, body) = get(addr, "/api/v1/switches/sw1");
+    assert!(header.starts_with("HTTP/1.0 404"));
+    assert_eq!(body, r#"{"error":"unknown target 'sw1'"}"#);
+}
+
+#[test]
+fn test_api_switch_error() {
+    let config: SimulatorConfig = toml::from_str(SWITCH).unwrap();
+    let sw = VirtualSwitch::new(config.switches[0].clone()).unwrap();
+    let addr = start(
+        mock(move |x| {
+            if x.ends_with(&[0x0c, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00]) {
+                None
+            } else {
+                sw.answer(x, Duration::from_secs(0)).ok()
+            }
+        }),
+        None,
+    );
+    let (_, body) = get(addr, "/api/v1/switches/sw1:eth0");
+    let state: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(state["up"], true);
+    assert_eq!(state["speed_stats"], serde_json::Value::Null);
+    assert!(!state["error"].as_str().unwrap().is_empty());
+
+    let addr = start(mock(|_| None), None);
+    let (_, body) = get(addr, "/api/v1/switches/sw1:eth0");
+    let state: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(state["up"], false);
+    assert!(!state["error"].as_str().unwrap().is_empty());
+}
+
+#[test]
+fn test_api_switches() {
+    let config = config(
+        r#"
+        [[target]]
+        name      = "switch1"
+        host      = "sw1"
+        interface = "eth0"
+
+        [[target]]
+        name      = "switch2"
+        host      = "sw2"
+        interface = "eth0"
+        "#,
+    );
+    let exporter = Exporter::new("127.0.0.1:0")
+        .connector(simulator(SWITCH))
+        .config(config);
+    let addr = serve(exporter, None);
+    let (_, body) = get(addr, "/api/v1/switches");
+
+    let states: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let states = states.as_array().unwrap();
+    assert_eq!(states.len(), 2);
+    assert_eq!(states[0]["target"], "switch1");
+    assert_eq!(states[1]["target"], "switch2");
+    assert_eq!(states[1]["up"], true);
+    assert_eq!(states[1]["last_success"], serde_json::Value::Null);
+
+    let (_, body) = get(addr, "/api/v1/switches/switch2");
+    let state: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(state["port_stats"]["stats"][0]["send_bytes"], 2000);
+}
+
+#[test]
+fn test_api_switches_configured() {
+    let config = config(
+        r#"
+        [[target]]
+        name      = "switch1"
+        host      = "sw1"
+        interface = "eth0"
+
+        [[target]]
+        name      = "switch2"
+        host      = "sw2"
+        interface = "eth0"
+        "#,
+    );
+    let exporter = Exporter::new("127.0.0.1:0")
+        .connector(simulator(SWITCH))
+        .config(config);
+
+    // The targets in the configuration file are listed after the static targets.
+    let addr = serve(exporter, Some("switch2"));
+    let (_, body) = get(addr, "/api/v1/switches");
+    let states: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let targets: Vec<_> = states
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| x["target"].as_str().unwrap())
+        .collect();
+    assert_eq!(targets, vec!["switch2", "switch1"]);
+}
+
+/// Reads Server-Sent Events until `count` events named `name` are received.
+fn read_events(addr: SocketAddr, path: &str, name: &str, count: usize) -> Vec<(String, String)> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+    let mut lines = BufReader::new(stream).lines().map(|x| x.unwrap());
+    assert!(lines.next().unwrap().starts_with("HTTP/1.0 200"));
+    let header: Vec<_> = lines.by_ref().take_while(|x| !x.is_empty()).collect();
+    assert!(header.contains(&String::from("content-type: text/event-stream")));
+
+    let mut events = Vec::new();
+    while events.iter().filter(|(x, _)| x == name).count() < count {
+        let event = lines.next().unwrap();
+        let data = lines.next().unwrap();
+        assert_eq!(lines.next().unwrap(), "");
+        events.push((
+            String::from(event.strip_prefix("event: ").unwrap()),
+            String::from(data.strip_prefix("data: ").unwrap()),
+        ));
+    }
+    events
+}
+
+#[test]
+fn test_stream() {
+    let addr = start(simulator(SWITCH), None);
+    let events = read_events(
+        addr,
+        "/api/v1/stream?target=sw1:eth0&interval=100",
+        "state",
+        2,
+    );
+    assert_eq!(events.len(), 2);
+
+    let state: serde_json::Value = serde_json::from_str(&events[0].1).unwrap();
+    assert_eq!(state["target"], "sw1:eth0");
+    assert_eq!(state["up"], true);
+    assert_eq!(state["port_stats"]["stats"][0]["recv_bytes"], 1000);
+    assert_eq!(state["rates"].as_array().unwrap().len(), 0);
+    let state: serde_json::Value = serde_json::from_str(&events[1].1).unwrap();
+    assert_eq!(state["rates"][0]["recv_bytes"], 0.0);
+
+    let (header, _) = get(addr, "/api/v1/stream?target=sw1:eth0&interval=10");
+    assert!(header.starts_with("HTTP/1.0 400"));
+    let (header, _) = get(addr, "/api/v1/stream?target=sw1:eth0&interval=1s");
+    assert!(header.starts_with("HTTP/1.0 400"));
+    let (header, _) = get(addr, "/api/v1/stream?target=sw1");
+    assert!(header.starts_with("HTTP/1.0 404"));
+}
+
+#[test]
+fn test_stream_shared() {
+    let sim: SimulatorConfig = toml::from_str(SWITCH).unwrap();
+    let sw = VirtualSwitch::new(sim.switches[0].clone()).unwrap();
+    let transport = Arc::new(MockTransport::new(move |x| {
+        sw.answer(x, Duration::from_secs(0)).ok()
+    }));
+    let connector: Connector = {
+        let transport = Arc::clone(&transport);
+        Arc::new(move |_, _| transport.clone())
+    };
+    let addr = start(connector, None);
+
+    let viewers: Vec<_> = (0..2)
+        .map(|_| {
+            thread::spawn(move || {
+                read_events(
+                    addr,
+                    "/api/v1/stream?target=sw1:eth0&interval=200",
+                    "state",
+                    3,
+                )
+            })
+        })
+        .collect();
+    for viewer in viewers {
+        assert_eq!(viewer.join().unwrap().len(), 3);
+    }
+    // Each poll sends 4 requests, and separate polls would need 2 viewers * 3 polls of them.
+    assert!(
+        transport.requests().len() < 24,
+        "{}",
+        transport.requests().len()
+    );
+}
+
+#[test]
+fn test_stream_link() {
+    let down = Arc::new(AtomicBool::new(false));
+    let sim: SimulatorConfig = toml::from_str(SWITCH).unwrap();
+    let up_sw = VirtualSwitch::new(sim.switches[0].clone()).unwrap();
+    let sim = SWITCH.replace("link     = \"100M\"", "link     = \"down\"");
+    let sim: SimulatorConfig = toml::from_str(&sim).unwrap();
+    let down_sw = VirtualSwitch::new(sim.switches[0].clone()).unwrap();
+    let connector = {
+        let down = Arc::clone(&down);
+        mock(move |x| {
+            let sw = if down.load(Ordering::SeqCst) {
+                &down_sw
+            } else {
+                &up_sw
+            };
+            sw.answer(x, Duration::from_secs(0)).ok()
+        })
+    };
+    let addr = start(connector, None);
+
+    let viewer = thread::spawn(move || {
+        read_events(
+            addr,
+            "/api/v1/stream?target=sw1:eth0&interval=100",
+            "link",
+            1,
+        )
+    });
+    thread::sleep(Duration::from_millis(300));
+    down.store(true, Ordering::SeqCst);
+
+    let events = viewer.join().unwrap();
+    let (name, data) = events.last().unwrap();
+    assert_eq!(name, "link");
+    assert_eq!(data, r#"{"port_no":2,"link":"down","previous":"100M"}"#);
+    assert!(events[..events.len() - 1].iter().all(|(x, _)| x == "state"));
+}
+
+#[test]
+fn test_landing_page() {
+    let addr = start(simulator(SWITCH), None);
+    let (header, body) = get(addr, "/");
+    assert!(header.contains("content-type: text/html; charset=utf-8"));
+    assert!(body.contains("<h1>ProSAFE Exporter</h1>"));
+    assert!(body.contains("<form action=\"/probe\">"));
+    assert!(body.contains("fetch(\"/api/v1/switches\")"));
+}
+
+#[test]
+fn test_exporter_metrics() {
+    let addr = start(simulator(SWITCH), None);
+    let (_, body) = get(addr, "/metrics");
+    assert!(body.contains("prosafe_exporter_probes_total{result=\"failure\"} 0\n"));
+    assert!(body.contains("prosafe_exporter_probes_total{result=\"success\"} 0\n"));
+
+    get(addr, "/probe?target=sw1:eth0");
+    get(addr, "/probe?target=sw1:eth0&debug=1");
+    let (_, body) = get(addr, "/metrics");
+    assert!(body.contains(&build_info()));
+    assert!(body.contains("prosafe_exporter_probes_total{result=\"success\"} 2\n"));
+    assert!(body.contains("prosafe_exporter_probe_duration_seconds_count 2\n"));
+    assert!(body.contains("prosafe_exporter_lock_wait_seconds_count 2\n"));
+    assert!(body.contains("prosafe_exporter_probes_in_flight 0\n"));
+    assert!(body.contains("process_start_time_seconds "));
+    assert!(!body.contains("prosafe_up"));
+}
+
+#[test]
+fn test_exporter_metrics_static_target() {
+    let addr = start(mock(|_| None), Some("sw1:eth0"));
+    let (_, body) = get(addr, "/metrics");
+    assert!(body.contains("prosafe_up{instance=\"sw1:eth0\",target=\"sw1:eth0\"} 0\n"));
+    assert!(!body.contains("prosafe_exporter_probes_total"));
+
+    let (_, body) = get(addr, "/exporter/metrics");
+    assert!(body.contains("prosafe_exporter_probes_total{result=\"failure\"} 1\n"));
+    assert!(!body.contains("prosafe_up"));
+}